@@ -1,8 +1,13 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
 use std::vec;
 
 use hyper::Body;
 use hyper::{body::HttpBody, Client};
+use hyper_rustls::HttpsConnectorBuilder;
 use hyperlocal::{UnixClientExt, Uri};
+use libsquish::paths;
 use libsquish::Result;
 
 #[derive(Debug)]
@@ -14,68 +19,146 @@ pub enum Method {
     Delete,
 }
 
-pub async fn get<S: Into<String>>(route: S) -> Result<String> {
-    request::<S, String>(Method::Get, route, None).await
-}
+/// An error response from squishd. The body is the error message, which is
+/// printed as-is.
+pub struct ApiError(String);
 
-pub async fn post<S: Into<String>, T: Into<String>>(route: S, body: Option<T>) -> Result<String> {
-    request(Method::Post, route, body).await
+impl fmt::Debug for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-#[allow(dead_code)]
-pub async fn put<S: Into<String>, T: Into<String>>(route: S, body: Option<T>) -> Result<String> {
-    request(Method::Put, route, body).await
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-#[allow(dead_code)]
-pub async fn patch<S: Into<String>, T: Into<String>>(route: S, body: Option<T>) -> Result<String> {
-    request(Method::Patch, route, body).await
+impl Error for ApiError {}
+
+/// Where squishd is reachable.
+enum Transport {
+    Unix(PathBuf),
+    /// The base URL of a squishd TCP listener, eg. `https://build-1:8443`.
+    Https(String),
 }
 
-#[allow(dead_code)]
-pub async fn delete<S: Into<String>, T: Into<String>>(route: S, body: Option<T>) -> Result<String> {
-    request(Method::Delete, route, body).await
+/// A client for squishd's API.
+pub struct SquishClient {
+    transport: Transport,
+    token: Option<String>,
 }
 
-pub async fn request<S: Into<String>, T: Into<String>>(
-    method: Method,
-    route: S,
-    body: Option<T>,
-) -> Result<String> {
-    let url: hyper::http::Uri = Uri::new("/tmp/squishd.sock", &route.into()).into();
-    let client = Client::unix();
-    let body = match body {
-        Some(s) => Body::from(s.into()),
-        None => Body::empty(),
-    };
-    let mut response = match method {
-        Method::Get => client.get(url).await?,
-        Method::Post => {
-            client
-                .request(hyper::Request::post(url).body(body)?)
-                .await?
+impl SquishClient {
+    /// Builds a client for the daemon at `host`, which is either a path to its
+    /// socket, a `unix://` URL or an `https://` URL. Defaults to the daemon's
+    /// default socket. `token` is sent as a bearer token if given.
+    pub fn new(host: Option<&str>, token: Option<&str>) -> Result<SquishClient> {
+        let transport = match host {
+            None => Transport::Unix(paths::default_socket_path()),
+            Some(host) => match host.split_once("://") {
+                Some(("unix", path)) => Transport::Unix(PathBuf::from(path)),
+                Some(("https", _)) => Transport::Https(host.trim_end_matches('/').to_string()),
+                Some((scheme, _)) => {
+                    return libsquish::err(format!("unsupported host scheme: {}", scheme))
+                }
+                None => Transport::Unix(PathBuf::from(host)),
+            },
+        };
+        Ok(SquishClient {
+            transport,
+            token: token.map(String::from),
+        })
+    }
+
+    pub async fn get<S: Into<String>>(&self, route: S) -> Result<String> {
+        self.request::<S, String>(Method::Get, route, None).await
+    }
+
+    pub async fn post<S: Into<String>, T: Into<Body>>(
+        &self,
+        route: S,
+        body: Option<T>,
+    ) -> Result<String> {
+        self.request(Method::Post, route, body).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn put<S: Into<String>, T: Into<Body>>(
+        &self,
+        route: S,
+        body: Option<T>,
+    ) -> Result<String> {
+        self.request(Method::Put, route, body).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn patch<S: Into<String>, T: Into<Body>>(
+        &self,
+        route: S,
+        body: Option<T>,
+    ) -> Result<String> {
+        self.request(Method::Patch, route, body).await
+    }
+
+    #[allow(dead_code)]
+    pub async fn delete<S: Into<String>, T: Into<Body>>(
+        &self,
+        route: S,
+        body: Option<T>,
+    ) -> Result<String> {
+        self.request(Method::Delete, route, body).await
+    }
+
+    pub async fn request<S: Into<String>, T: Into<Body>>(
+        &self,
+        method: Method,
+        route: S,
+        body: Option<T>,
+    ) -> Result<String> {
+        let route = route.into();
+        let url: hyper::http::Uri = match &self.transport {
+            Transport::Unix(socket) => Uri::new(socket, &route).into(),
+            Transport::Https(base) => format!("{}{}", base, route).parse()?,
+        };
+        let mut request = hyper::Request::builder().uri(url).method(match method {
+            Method::Get => hyper::Method::GET,
+            Method::Post => hyper::Method::POST,
+            Method::Put => hyper::Method::PUT,
+            Method::Patch => hyper::Method::PATCH,
+            Method::Delete => hyper::Method::DELETE,
+        });
+        if let Some(token) = &self.token {
+            request = request.header("authorization", format!("Bearer {}", token));
         }
-        Method::Put => client.request(hyper::Request::put(url).body(body)?).await?,
-        Method::Patch => {
-            client
-                .request(hyper::Request::patch(url).body(body)?)
-                .await?
+        let request = request.body(match body {
+            Some(body) => body.into(),
+            None => Body::empty(),
+        })?;
+        let mut response = match &self.transport {
+            Transport::Unix(_) => Client::unix().request(request).await?,
+            Transport::Https(_) => {
+                let https = HttpsConnectorBuilder::new()
+                    .with_native_roots()
+                    .https_only()
+                    .enable_http1()
+                    .build();
+                Client::builder().build(https).request(request).await?
+            }
+        };
+        let mut body: Vec<u8> = vec![];
+        while let Some(next) = response.data().await {
+            let chunk = next?;
+            let bytes: Vec<u8> = chunk.to_vec();
+            body.extend(&bytes);
+            // io::stdout().write_all(&chunk).await?;
         }
-        Method::Delete => {
-            client
-                .request(hyper::Request::delete(url).body(body)?)
-                .await?
+        // The server should never send back invalid UTF-8
+        let body = String::from_utf8(body).unwrap();
+        if response.status().is_client_error() || response.status().is_server_error() {
+            return Err(ApiError(body).into());
         }
-        #[allow(unreachable_patterns)]
-        _ => panic!("unimplemented method: {:?}", method),
-    };
-    let mut body: Vec<u8> = vec![];
-    while let Some(next) = response.data().await {
-        let chunk = next?;
-        let bytes: Vec<u8> = chunk.to_vec();
-        body.extend(&bytes);
-        // io::stdout().write_all(&chunk).await?;
+        Ok(body)
     }
-    // The server should never send back invalid UTF-8
-    Ok(String::from_utf8(body).unwrap())
 }
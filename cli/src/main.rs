@@ -8,8 +8,11 @@ extern crate tokio;
 mod client;
 
 use std::cmp::max;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgGroup, Command};
 use libsquish::squishfile;
 use libsquish::Result;
 
@@ -17,11 +20,44 @@ use libsquish::Result;
 async fn main() -> Result<()> {
     let matches = Command::new("squish")
         .arg(Arg::new("debug").long("debug").short('d').help(""))
+        .arg(
+            Arg::new("host")
+                .long("host")
+                .short('H')
+                .env("SQUISH_HOST")
+                .takes_value(true)
+                .global(true)
+                .help("squishd to connect to, as a socket path, unix:// URL or https:// URL"),
+        )
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .env("SQUISH_TOKEN")
+                .takes_value(true)
+                .global(true)
+                .help("Bearer token to authenticate to squishd with"),
+        )
         .subcommand(Command::new("ps").about("List running containers"))
+        .subcommand(Command::new("info").about("Show information about squishd"))
         .subcommand(
             Command::new("create")
                 .about("Create new containers")
-                .arg(Arg::new("squishfile").required(true)),
+                .arg(Arg::new("squishfile").required(true))
+                .arg(
+                    Arg::new("replicas")
+                        .long("replicas")
+                        .short('r')
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("Number of containers to create from the squishfile"),
+                )
+                .arg(
+                    Arg::new("name")
+                        .long("name")
+                        .short('n')
+                        .takes_value(true)
+                        .help("Name for the container, suffixed with the index of each replica"),
+                ),
         )
         .subcommand(
             Command::new("validate")
@@ -30,79 +66,341 @@ async fn main() -> Result<()> {
         )
         .subcommand(
             Command::new("stop")
-                .about("Stop a container")
+                .about("Stop a container, or several with --all or --filter")
+                .arg(Arg::new("id"))
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .short('a')
+                        .help("Stop every container"),
+                )
+                .arg(
+                    Arg::new("filter")
+                        .long("filter")
+                        .short('f')
+                        .takes_value(true)
+                        .help("Stop every container matching name=<prefix>, status=<status> or label=<key>[=<value>]"),
+                )
+                .group(
+                    ArgGroup::new("target")
+                        .args(&["id", "all", "filter"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .short('t')
+                        .takes_value(true)
+                        .help("Seconds to wait for the container to exit before killing it"),
+                ),
+        )
+        .subcommand(
+            Command::new("kill")
+                .about("Send a signal to a container's workload")
+                .arg(Arg::new("id").required(true))
+                .arg(
+                    Arg::new("signal")
+                        .long("signal")
+                        .short('s')
+                        .takes_value(true)
+                        .default_value("KILL")
+                        .help("Signal to send, by name or number"),
+                ),
+        )
+        .subcommand(
+            Command::new("pause")
+                .about("Freeze every process in a container")
+                .arg(Arg::new("id").required(true)),
+        )
+        .subcommand(
+            Command::new("unpause")
+                .about("Resume a paused container")
+                .arg(Arg::new("id").required(true)),
+        )
+        .subcommand(
+            Command::new("restart")
+                .about("Stop a container and start it again from its squishfile")
+                .arg(Arg::new("id").required(true)),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Show live resource usage of containers")
+                .arg(
+                    Arg::new("id")
+                        .multiple_values(true)
+                        .help("Containers to show [default: all]"),
+                )
+                .arg(
+                    Arg::new("no-stream")
+                        .long("no-stream")
+                        .help("Print a single snapshot instead of updating live"),
+                ),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Manage squishd's download cache")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("import")
+                        .about("Import an Alpine minirootfs tarball or slirp4netns binary, eg. for running offline")
+                        .arg(Arg::new("file").required(true))
+                        .arg(
+                            Arg::new("alpine-version")
+                                .long("alpine-version")
+                                .takes_value(true)
+                                .help("Alpine version to import a tarball as [default: from the tarball]"),
+                        )
+                        .arg(
+                            Arg::new("alpine-arch")
+                                .long("alpine-arch")
+                                .takes_value(true)
+                                .help("Alpine architecture to import a tarball as [default: from the file name]"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("verify")
+                        .about("Check cached files against their recorded checksums, quarantining any that don't match"),
+                ),
+        )
+        .subcommand(
+            Command::new("wait")
+                .about("Wait for a container to exit, exiting with its exit code")
                 .arg(Arg::new("id").required(true)),
         )
         .get_matches();
+    let client = client::SquishClient::new(matches.value_of("host"), matches.value_of("token"))?;
 
     match matches.subcommand_name() {
         Some("ps") => {
-            let res = client::get("/containers/list").await?;
-            let value: Vec<libsquish::RunningContainer> =
-                serde_json::from_str(res.as_str()).unwrap();
+            let res = client.get("/containers/list").await?;
+            let value: Vec<libsquish::RunningContainer> = match serde_json::from_str(&res) {
+                Ok(value) => value,
+                Err(_) => {
+                    eprintln!("got unknown value: {}", res);
+                    std::process::exit(1);
+                }
+            };
 
             let mut max_name = 0;
             for container in &value {
                 max_name = max(container.name.len(), max_name);
             }
             println!(
-                "{:id_width$} {:name_width$} PID",
+                "{:id_width$} {:name_width$} {:pid_width$} STATUS",
                 "ID",
                 "NAME",
+                "PID",
                 id_width = 7,
-                name_width = max_name
+                name_width = max_name,
+                pid_width = 7
             );
             for container in &value {
+                let status = match &container.exit {
+                    Some(exit) => match (exit.code, exit.signal) {
+                        (_, Some(signal)) => format!("{} (signal {})", container.status, signal),
+                        (code, None) => format!("{} ({})", container.status, code.unwrap_or(-1)),
+                    },
+                    None => container.status.to_string(),
+                };
                 println!(
-                    "{} {:name_width$} {}",
+                    "{} {:name_width$} {:pid_width$} {}",
                     &container.id[..7],
                     container.name,
                     container.pid,
-                    name_width = max_name
+                    status,
+                    name_width = max_name,
+                    pid_width = 7
                 );
             }
         }
         Some("create") => {
             // safe
-            let path = matches
-                .subcommand_matches("create")
+            let create_matches = matches.subcommand_matches("create").ok_or("impossible")?;
+            let path = create_matches.value_of("squishfile").ok_or("impossible")?;
+            let replicas: usize = create_matches
+                .value_of("replicas")
                 .ok_or("impossible")?
-                .value_of("squishfile")
-                .ok_or("impossible")?;
+                .parse()
+                .map_err(|_| "--replicas must be a number")?;
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            query.append_pair("replicas", &replicas.to_string());
+            if let Some(name) = create_matches.value_of("name") {
+                query.append_pair("name", name);
+            }
             let mut squishfile = squishfile::parse(path)?;
             squishfile.resolve_paths();
 
             // Send to daemon
-            let res = client::post(
-                "/containers/create",
-                Some(serde_json::to_string(&squishfile)?),
-            )
-            .await?;
-            let ids: serde_json::Value = serde_json::from_str(res.as_str())?;
-            for id in ids.as_array().unwrap() {
-                println!("{}", id.as_str().unwrap());
+            let res = client
+                .post(
+                    format!("/containers/create?{}", query.finish()),
+                    Some(serde_json::to_string(&squishfile)?),
+                )
+                .await?;
+            match serde_json::from_str::<Vec<libsquish::CreatedContainer>>(res.as_str()) {
+                Ok(containers) => {
+                    for container in containers {
+                        let ports: Vec<String> = container
+                            .ports
+                            .iter()
+                            .map(|port| {
+                                format!("{}->{}/{}", port.host(), port.container(), port.protocol())
+                            })
+                            .collect();
+                        if ports.is_empty() {
+                            println!("{}", container.id);
+                        } else {
+                            println!("{} {}", container.id, ports.join(", "));
+                        }
+                    }
+                }
+                Err(_) => eprintln!("got unknown value: {}", res),
             }
         }
         Some("stop") => {
+            // safe
+            let stop_matches = matches.subcommand_matches("stop").ok_or("impossible")?;
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            if let Some(timeout) = stop_matches.value_of("timeout") {
+                query.append_pair("timeout", &timeout.parse::<u64>()?.to_string());
+            }
+            let route = match stop_matches.value_of("id") {
+                Some(container_id) => format!("/containers/stop/{}", container_id),
+                None => {
+                    if stop_matches.is_present("all") {
+                        query.append_pair("all", "true");
+                    }
+                    if let Some(filter) = stop_matches.value_of("filter") {
+                        query.append_pair("filter", filter);
+                    }
+                    "/containers/stop".to_string()
+                }
+            };
+            let route = format!("{}?{}", route, query.finish());
+
+            // Send to daemon
+            let res = client.post::<String, String>(route, None).await?;
+            match serde_json::from_str::<Vec<libsquish::StoppedContainer>>(res.as_str()) {
+                Ok(stopped) => {
+                    for container in stopped {
+                        match container.signal {
+                            Some(signal) => println!("{} ({})", container.id, signal),
+                            None => println!("{}", container.id),
+                        }
+                    }
+                }
+                Err(_) => eprintln!("got unknown value: {}", res),
+            }
+        }
+        Some("kill") => {
+            // safe
+            let kill_matches = matches.subcommand_matches("kill").ok_or("impossible")?;
+            let container_id = kill_matches.value_of("id").ok_or("impossible")?;
+            let signal = kill_matches.value_of("signal").ok_or("impossible")?;
+
+            // Send to daemon
+            let res = client
+                .post::<String, String>(
+                    format!("/containers/{}/kill?signal={}", container_id, signal),
+                    None,
+                )
+                .await?;
+            match serde_json::from_str::<libsquish::SignalledContainer>(res.as_str()) {
+                Ok(container) => println!("{}", container.id),
+                Err(_) => eprintln!("got unknown value: {}", res),
+            }
+        }
+        Some(cmd @ "pause") | Some(cmd @ "unpause") | Some(cmd @ "restart") => {
             // safe
             let container_id = matches
-                .subcommand_matches("stop")
+                .subcommand_matches(cmd)
                 .ok_or("impossible")?
                 .value_of("id")
                 .ok_or("impossible")?;
 
             // Send to daemon
-            let res =
-                client::post::<String, String>(format!("/containers/stop/{}", container_id), None)
-                    .await?;
-            let ids = serde_json::from_str(res.as_str())?;
-            match ids {
-                serde_json::Value::Array(ids) => {
-                    for id in ids {
-                        println!("{}", id.as_str().unwrap());
-                    }
+            let res = client
+                .post::<String, String>(format!("/containers/{}/{}", container_id, cmd), None)
+                .await?;
+            match serde_json::from_str::<libsquish::RunningContainer>(res.as_str()) {
+                Ok(container) => println!("{}", container.id),
+                Err(_) => eprintln!("got unknown value: {}", res),
+            }
+        }
+        Some("wait") => {
+            // safe
+            let container_id = matches
+                .subcommand_matches("wait")
+                .ok_or("impossible")?
+                .value_of("id")
+                .ok_or("impossible")?;
+
+            // Send to daemon. This blocks until the container exits.
+            let res = client
+                .post::<String, String>(format!("/containers/{}/wait", container_id), None)
+                .await?;
+            match serde_json::from_str::<libsquish::ExitStatus>(res.as_str()) {
+                Ok(exit) => std::process::exit(exit.shell_code()),
+                Err(_) => {
+                    eprintln!("got unknown value: {}", res);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("stats") => {
+            // safe
+            let stats_matches = matches.subcommand_matches("stats").ok_or("impossible")?;
+            let ids: Vec<String> = stats_matches
+                .values_of("id")
+                .map(|ids| ids.map(String::from).collect())
+                .unwrap_or_default();
+            show_stats(&client, &ids, !stats_matches.is_present("no-stream")).await?;
+        }
+        Some("info") => {
+            let res = client.get("/status").await?;
+            match serde_json::from_str::<libsquish::DaemonStatus>(&res) {
+                Ok(status) => print_info(&status),
+                Err(_) => {
+                    eprintln!("got unknown value: {}", res);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("cache") => {
+            // safe
+            let cache_matches = matches.subcommand_matches("cache").ok_or("impossible")?;
+            if cache_matches.subcommand_matches("verify").is_some() {
+                verify_cache(&client).await?;
+                return Ok(());
+            }
+            let import_matches = cache_matches
+                .subcommand_matches("import")
+                .ok_or("impossible")?;
+            let path = Path::new(import_matches.value_of("file").ok_or("impossible")?);
+            let mut query = form_urlencoded::Serializer::new(String::new());
+            if let Some(file) = path.file_name() {
+                query.append_pair("file", &file.to_string_lossy());
+            }
+            if let Some(version) = import_matches.value_of("alpine-version") {
+                query.append_pair("version", version);
+            }
+            if let Some(arch) = import_matches.value_of("alpine-arch") {
+                query.append_pair("arch", arch);
+            }
+            let bytes = std::fs::read(path)?;
+
+            // Send to daemon
+            let res = client
+                .post(format!("/cache/import?{}", query.finish()), Some(bytes))
+                .await?;
+            match serde_json::from_str::<libsquish::ImportedFile>(&res) {
+                Ok(libsquish::ImportedFile::Alpine(rootfs)) => {
+                    println!("imported alpine {} ({})", rootfs.version, rootfs.arch)
                 }
-                _ => eprintln!("got unknown value: {}", res),
+                Ok(libsquish::ImportedFile::Slirp4netns { version }) => {
+                    println!("imported slirp4netns {}", version)
+                }
+                Err(_) => eprintln!("got unknown value: {}", res),
             }
         }
         Some("validate") => {
@@ -125,3 +423,205 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Prints how each cached file fared, exiting unsuccessfully if any of them
+/// were bad.
+async fn verify_cache(client: &client::SquishClient) -> Result<()> {
+    let res = client.post::<&str, String>("/cache/verify", None).await?;
+    let verified = match serde_json::from_str::<Vec<libsquish::VerifiedFile>>(&res) {
+        Ok(verified) => verified,
+        Err(_) => {
+            eprintln!("got unknown value: {}", res);
+            std::process::exit(1);
+        }
+    };
+    let mut bad = false;
+    for file in &verified {
+        match &file.quarantined {
+            Some(quarantined) => println!(
+                "{:10} {} (quarantined as {})",
+                file.status.to_string(),
+                file.path,
+                quarantined
+            ),
+            None => println!("{:10} {}", file.status.to_string(), file.path),
+        }
+        bad |= matches!(
+            file.status,
+            libsquish::VerifyStatus::Mismatch | libsquish::VerifyStatus::Unreadable
+        );
+    }
+    if bad {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn print_info(status: &libsquish::DaemonStatus) {
+    let yes_no = |enabled: bool| if enabled { "yes" } else { "no" };
+    let alpine = if status.alpine.is_empty() {
+        "none cached".to_string()
+    } else {
+        status
+            .alpine
+            .iter()
+            .map(|rootfs| format!("{} ({})", rootfs.version, rootfs.arch))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    println!("Version:         {}", status.version);
+    println!("Architecture:    {}", status.arch);
+    println!("Uptime:          {}", human_duration(status.uptime_secs));
+    println!("Socket:          {}", status.socket);
+    println!("State directory: {}", status.state_dir);
+    println!("Cache directory: {}", status.cache_dir);
+    println!("Alpine:          {}", alpine);
+    println!(
+        "slirp4netns:     {}",
+        status.slirp4netns.as_deref().unwrap_or("unavailable")
+    );
+    println!("Containers:      {}", status.containers);
+    println!("Kernel features:");
+    println!(
+        "  Unprivileged user namespaces: {}",
+        yes_no(status.features.unprivileged_userns)
+    );
+    println!(
+        "  cgroup v2 delegation:         {}",
+        yes_no(status.features.cgroup_delegation)
+    );
+    println!(
+        "  pidfd:                        {}",
+        yes_no(status.features.pidfd)
+    );
+}
+
+fn human_duration(secs: u64) -> String {
+    let (days, hours, minutes, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, secs)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// How often `squish stats` samples containers.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Prints the resource usage of the given containers, or of every container if
+/// none are given. CPU usage is worked out from the difference between two
+/// samples, so the first table is printed after one interval. When streaming,
+/// the table is redrawn every interval until interrupted.
+async fn show_stats(client: &client::SquishClient, ids: &[String], stream: bool) -> Result<()> {
+    let mut previous: HashMap<String, libsquish::ContainerStats> = HashMap::new();
+    let mut first = true;
+    loop {
+        let targets = if ids.is_empty() {
+            let res = client.get("/containers/list").await?;
+            match serde_json::from_str::<Vec<libsquish::RunningContainer>>(&res) {
+                Ok(containers) => containers
+                    .into_iter()
+                    .filter(|container| container.status != libsquish::ContainerStatus::Exited)
+                    .map(|container| container.id)
+                    .collect(),
+                Err(_) => {
+                    eprintln!("got unknown value: {}", res);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            ids.to_vec()
+        };
+
+        let mut samples = vec![];
+        for id in &targets {
+            let res = client.get(format!("/containers/{}/stats", id)).await?;
+            match serde_json::from_str::<libsquish::ContainerStats>(&res) {
+                Ok(stats) => samples.push(stats),
+                Err(_) => eprintln!("got unknown value: {}", res),
+            }
+        }
+
+        if !first {
+            if stream {
+                // Clear the screen and move the cursor to the top left
+                print!("\x1b[2J\x1b[H");
+            }
+            print_stats(&samples, &previous);
+            if !stream {
+                return Ok(());
+            }
+        }
+        first = false;
+        previous = samples
+            .into_iter()
+            .map(|stats| (stats.id.clone(), stats))
+            .collect();
+        tokio::time::sleep(STATS_INTERVAL).await;
+    }
+}
+
+fn print_stats(
+    samples: &[libsquish::ContainerStats],
+    previous: &HashMap<String, libsquish::ContainerStats>,
+) {
+    let name_width = samples
+        .iter()
+        .map(|stats| stats.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "{:7} {:name_width$} {:>7} {:>10} {:>5} {:>5} {:>10} {:>10}",
+        "ID",
+        "NAME",
+        "CPU %",
+        "MEM",
+        "PIDS",
+        "FDS",
+        "NET RX",
+        "NET TX",
+        name_width = name_width
+    );
+    for stats in samples {
+        let cpu = match previous.get(&stats.id) {
+            Some(before) if stats.timestamp > before.timestamp => {
+                let used = stats.cpu_time_ms.saturating_sub(before.cpu_time_ms) as f64;
+                let elapsed = (stats.timestamp - before.timestamp) as f64;
+                format!("{:.1}", used / elapsed * 100.0)
+            }
+            _ => "-".to_string(),
+        };
+        println!(
+            "{:7} {:name_width$} {:>7} {:>10} {:>5} {:>5} {:>10} {:>10}",
+            &stats.id[..7],
+            stats.name,
+            cpu,
+            human_bytes(stats.memory_bytes),
+            stats.processes,
+            stats.open_fds,
+            human_bytes(stats.rx_bytes),
+            human_bytes(stats.tx_bytes),
+            name_width = name_width
+        );
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
@@ -0,0 +1,240 @@
+use crate::config;
+use crate::util::SquishError;
+
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use libsquish::squishfile::Squishfile;
+use libsquish::SyncResult;
+use nix::unistd::{getgrouplist, getuid, Gid, Uid, User};
+use serde::Deserialize;
+use tokio::net::unix::UCred;
+use warp::{Filter, Rejection};
+
+static TOKENS: OnceLock<Vec<Token>> = OnceLock::new();
+
+/// Who an API request came from. Unix socket connections get this attached to
+/// every request once the peer on the other end has been authorised; requests
+/// over TCP get it from their bearer token.
+#[derive(Debug, Clone, Copy)]
+pub struct Caller {
+    pub uid: u32,
+    /// Admins can see and manage every container, not just their own.
+    pub admin: bool,
+    pub scope: Scope,
+}
+
+/// What a caller is allowed to do to the containers it can see.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Listing, waiting on and watching containers.
+    Read,
+    /// Everything, including creating and stopping containers.
+    Manage,
+}
+
+impl Caller {
+    /// Whether this caller may see and manage a container owned by `owner`.
+    pub fn can_access(&self, owner: u32) -> bool {
+        self.admin || self.uid == owner
+    }
+}
+
+/// A bearer token accepted by the TCP listener, as read from the token file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Token {
+    /// A human-readable name for the token, for logs.
+    name: String,
+    token: String,
+    scope: Scope,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenFile {
+    #[serde(default, rename = "token")]
+    tokens: Vec<Token>,
+}
+
+/// Works out whether the peer on the other end of a unix socket connection may
+/// use the API, based on its `SO_PEERCRED` credentials. squishd's own user and
+/// root are always allowed, and are always admins.
+pub fn authorise(cred: &UCred) -> Option<Caller> {
+    let auth = &config::get().auth;
+    let uid = cred.uid();
+    let gids = peer_groups(cred);
+    let in_any = |allowed: &[u32]| gids.iter().any(|gid| allowed.contains(gid));
+
+    let admin = uid == 0
+        || uid == getuid().as_raw()
+        || auth.admin_uids.contains(&uid)
+        || in_any(&auth.admin_gids);
+    if admin || auth.allowed_uids.contains(&uid) || in_any(&auth.allowed_gids) {
+        Some(Caller {
+            uid,
+            admin,
+            scope: Scope::Manage,
+        })
+    } else {
+        None
+    }
+}
+
+/// The peer's primary group, plus the supplementary groups its user is in.
+/// These come from the user database rather than the peer process, which may
+/// have exited and had its pid reused by the time anyone looks.
+fn peer_groups(cred: &UCred) -> Vec<u32> {
+    user_groups(cred.uid(), Some(cred.gid()))
+}
+
+/// Every group a user is in according to the user database, including
+/// `primary`, or the user's own primary group if that isn't given. Users who
+/// aren't in the database are only in `primary`.
+fn user_groups(uid: u32, primary: Option<u32>) -> Vec<u32> {
+    let user = match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => user,
+        _ => return primary.into_iter().collect(),
+    };
+    let primary = primary.unwrap_or_else(|| user.gid.as_raw());
+    let groups = CString::new(user.name)
+        .ok()
+        .and_then(|name| getgrouplist(&name, Gid::from_raw(primary)).ok());
+    match groups {
+        Some(groups) => groups.into_iter().map(Gid::as_raw).collect(),
+        None => vec![primary],
+    }
+}
+
+/// Makes sure that a caller who isn't an admin only bind-mounts paths they
+/// could get at themselves, since pid1 mounts them as squishd's own user.
+/// Each layer path has to be absolute, exist, and either belong to the caller
+/// or be readable by them, and writable too for a read-write layer. Paths are
+/// replaced with their canonical form, so that a symlink can't be pointed
+/// somewhere else between this check and the mount.
+pub fn check_layer_paths(caller: &Caller, squishfile: &mut Squishfile) -> Result<(), SquishError> {
+    if caller.admin {
+        return Ok(());
+    }
+    let groups = user_groups(caller.uid, None);
+    let mut canonical = vec![];
+    for (name, layer) in squishfile.layers() {
+        let path = match layer.path() {
+            // The alpine layer is never mounted from the host
+            Some(path) if name != "alpine" => path,
+            _ => continue,
+        };
+        let forbidden = || SquishError::ForbiddenPath(path.clone());
+        if !Path::new(path).is_absolute() {
+            return Err(forbidden());
+        }
+        let resolved = fs::canonicalize(path).map_err(|_| forbidden())?;
+        let want = if matches!(layer.rw(), Some(true)) {
+            0o6
+        } else {
+            0o4
+        };
+        if !may_access(&resolved, caller.uid, &groups, want) {
+            return Err(forbidden());
+        }
+        canonical.push((name.clone(), resolved.display().to_string()));
+    }
+    for (name, path) in canonical {
+        squishfile.set_layer_path(&name, path);
+    }
+    Ok(())
+}
+
+/// Whether a user could access `path` with the given permission bits
+/// themselves: they have to be able to search every directory above it, and
+/// either own it or have the bits through its mode.
+fn may_access(path: &Path, uid: u32, groups: &[u32], want: u32) -> bool {
+    let permits = |path: &Path, want: u32| match fs::metadata(path) {
+        Ok(metadata) => {
+            let mode = metadata.mode();
+            let bits = if metadata.uid() == uid {
+                mode >> 6
+            } else if groups.contains(&metadata.gid()) {
+                mode >> 3
+            } else {
+                mode
+            };
+            bits & want == want
+        }
+        Err(_) => false,
+    };
+    let owned = fs::metadata(path).is_ok_and(|metadata| metadata.uid() == uid);
+    path.ancestors().skip(1).all(|dir| permits(dir, 0o1)) && (owned || permits(path, want))
+}
+
+/// Loads the bearer tokens accepted by the TCP listener. This must be called
+/// at most once, before the TCP listener is started.
+pub fn load_tokens(path: &Path) -> SyncResult<()> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        warn!(
+            "token file {} is readable by other users (mode {:o})",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    let contents = fs::read_to_string(path)?;
+    let file: TokenFile = toml::from_str(&contents)
+        .map_err(|e| format!("invalid token file {}: {}", path.display(), e))?;
+    info!(
+        "loaded {} api token(s) from {}",
+        file.tokens.len(),
+        path.display()
+    );
+    if TOKENS.set(file.tokens).is_err() {
+        panic!("api tokens were already loaded");
+    }
+    Ok(())
+}
+
+/// Finds the caller that a bearer token belongs to. Token holders act as
+/// squishd's own user, and can see every container.
+fn authorise_token(header: &str) -> Option<Caller> {
+    let presented = header.strip_prefix("Bearer ")?.trim();
+    // Compare digests rather than the tokens themselves, so that how long a
+    // comparison takes doesn't give away how much of a token was right.
+    let presented = hmac_sha256::Hash::hash(presented.as_bytes());
+    let token = TOKENS
+        .get()?
+        .iter()
+        .find(|token| hmac_sha256::Hash::hash(token.token.as_bytes()) == presented)?;
+    debug!("request authorised by token {}", token.name);
+    Some(Caller {
+        uid: getuid().as_raw(),
+        admin: true,
+        scope: token.scope,
+    })
+}
+
+/// Extracts the caller of a request, rejecting the request if it came from an
+/// unauthorised connection without a valid bearer token.
+pub fn caller() -> impl Filter<Extract = (Caller,), Error = Rejection> + Clone {
+    warp::ext::optional::<Caller>()
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            |caller: Option<Caller>, authorization: Option<String>| async move {
+                caller
+                    .or_else(|| authorise_token(authorization.as_deref()?))
+                    .ok_or_else(|| warp::reject::custom(SquishError::Forbidden))
+            },
+        )
+}
+
+/// Like `caller()`, but also rejects callers that may only read.
+pub fn manager() -> impl Filter<Extract = (Caller,), Error = Rejection> + Clone {
+    caller().and_then(|caller: Caller| async move {
+        match caller.scope {
+            Scope::Manage => Ok(caller),
+            Scope::Read => Err(warp::reject::custom(SquishError::ReadOnly)),
+        }
+    })
+}
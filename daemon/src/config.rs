@@ -0,0 +1,224 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use libsquish::paths;
+use libsquish::SyncResult;
+use serde::Deserialize;
+
+use crate::engine::alpine;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// squishd's configuration, as read from `squishd.toml`. Every option is
+/// optional, and command-line flags take precedence over the file. Relative
+/// paths are relative to squishd's working directory.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The unix socket to serve the API on. Defaults to `squishd.sock` in the
+    /// runtime directory.
+    pub socket: Option<PathBuf>,
+    /// Where containers' directories live. Defaults to
+    /// `$XDG_STATE_HOME/squish`.
+    pub state_dir: PathBuf,
+    /// Where downloaded Alpine rootfs images and binaries are cached. Defaults
+    /// to `$XDG_CACHE_HOME/squish`.
+    pub cache_dir: PathBuf,
+    /// Where sockets go. Defaults to `$XDG_RUNTIME_DIR/squish`.
+    pub runtime_dir: PathBuf,
+    /// The pid1 binary used to launch containers. Defaults to the `pid1` that
+    /// sits next to the squishd binary.
+    pub pid1: Option<PathBuf>,
+    /// Leaves containers running when squishd shuts down, rather than
+    /// stopping them.
+    pub keep_containers: bool,
+    /// Downloads the default Alpine rootfs and slirp4netns at boot, rather
+    /// than when the first container needs them.
+    pub prefetch: bool,
+    /// Never downloads anything. Anything that isn't already cached has to be
+    /// imported with `squish cache import`.
+    pub offline: bool,
+    pub alpine: AlpineConfig,
+    pub slirp: SlirpConfig,
+    pub auth: AuthConfig,
+    pub tcp: TcpConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlpineConfig {
+    /// The Alpine version used by squishfiles that don't ask for one.
+    pub version: String,
+    /// The architecture used by squishfiles that don't ask for one. Defaults
+    /// to the host's.
+    pub arch: String,
+    /// Mirrors to download Alpine from, in order of preference. `file://`
+    /// mirrors are local directories laid out like a real mirror.
+    pub mirrors: Vec<String>,
+    /// How long a single request to a mirror may take.
+    pub mirror_timeout_secs: u64,
+    /// How many more times to try a mirror before moving on to the next.
+    pub mirror_retries: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlirpConfig {
+    /// A slirp4netns binary to use instead of downloading one into the cache.
+    pub binary: Option<PathBuf>,
+    /// The sha256 digest, as hex, that the downloaded slirp4netns binary must
+    /// have, instead of the one pinned for the host's architecture.
+    pub sha256: Option<String>,
+    pub mtu: u32,
+    /// Stops containers from reaching the host's loopback interface via
+    /// 10.0.2.2.
+    pub disable_host_loopback: bool,
+    /// The host address that port forwards listen on.
+    pub host_ip: IpAddr,
+}
+
+/// Who may connect to the unix socket. squishd's own user and root may always
+/// connect, and are always admins. Groups match against a caller's primary and
+/// supplementary groups.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+    /// Admins can see and manage every container, rather than only the ones
+    /// they created.
+    pub admin_uids: Vec<u32>,
+    pub admin_gids: Vec<u32>,
+}
+
+/// An optional TCP listener, for managing squishd over the network. It's only
+/// ever served over TLS, and every request must carry a bearer token from the
+/// token file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    /// The address to listen on, eg. `0.0.0.0:8443`. The listener is off
+    /// unless this is set.
+    pub listen: Option<SocketAddr>,
+    /// PEM-encoded certificate chain and private key.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// A TOML file of `[[token]]`s, each with a `name`, `token` and `scope`
+    /// of either `read` or `manage`.
+    pub tokens: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            socket: None,
+            state_dir: paths::state_dir(),
+            cache_dir: paths::cache_dir(),
+            runtime_dir: paths::runtime_dir(),
+            pid1: None,
+            keep_containers: false,
+            prefetch: true,
+            offline: false,
+            alpine: AlpineConfig::default(),
+            slirp: SlirpConfig::default(),
+            auth: AuthConfig::default(),
+            tcp: TcpConfig::default(),
+        }
+    }
+}
+
+impl Default for AlpineConfig {
+    fn default() -> Self {
+        AlpineConfig {
+            version: alpine::VERSION.to_string(),
+            arch: alpine::host_arch().to_string(),
+            mirrors: alpine::MIRRORS
+                .iter()
+                .map(|mirror| mirror.to_string())
+                .collect(),
+            mirror_timeout_secs: 30,
+            mirror_retries: 1,
+        }
+    }
+}
+
+impl Default for SlirpConfig {
+    fn default() -> Self {
+        SlirpConfig {
+            binary: None,
+            sha256: None,
+            mtu: 65520,
+            disable_host_loopback: true,
+            host_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        }
+    }
+}
+
+impl AuthConfig {
+    /// Whether anyone other than squishd's own user and root may connect.
+    pub fn allows_others(&self) -> bool {
+        !(self.allowed_uids.is_empty()
+            && self.allowed_gids.is_empty()
+            && self.admin_uids.is_empty()
+            && self.admin_gids.is_empty())
+    }
+}
+
+impl Config {
+    /// Loads the config file at `path`, or from the default location if no
+    /// path is given. A missing file at the default location just means the
+    /// defaults are used, but an explicitly-requested file has to exist.
+    pub fn load(path: Option<&Path>) -> SyncResult<Config> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => (default_path(), false),
+        };
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                info!("loading config from {}", path.display());
+                toml::from_str(&contents)
+                    .map_err(|e| format!("invalid config {}: {}", path.display(), e).into())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound && !required => Ok(Config::default()),
+            Err(e) => Err(format!("couldn't read config {}: {}", path.display(), e).into()),
+        }
+    }
+
+    pub fn socket(&self) -> PathBuf {
+        self.socket
+            .clone()
+            .unwrap_or_else(|| self.runtime_dir.join("squishd.sock"))
+    }
+
+    pub fn pid1(&self) -> PathBuf {
+        match &self.pid1 {
+            Some(pid1) => pid1.clone(),
+            None => std::env::current_exe()
+                .map(|exe| exe.with_file_name("pid1"))
+                .unwrap_or_else(|_| PathBuf::from("pid1")),
+        }
+    }
+
+    /// The directory that container directories are created in.
+    pub fn containers_dir(&self) -> PathBuf {
+        self.state_dir.join("containers")
+    }
+}
+
+pub fn default_path() -> PathBuf {
+    paths::config_dir().join("squishd.toml")
+}
+
+/// Sets the global config. This must be called exactly once, before anything
+/// calls `get()`.
+pub fn init(config: Config) {
+    CONFIG.set(config).expect("config was already initialised");
+}
+
+/// Returns the global config.
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config wasn't initialised")
+}
@@ -1,23 +1,103 @@
+use crate::config;
+use crate::engine::checksum::{self, Digest};
+use crate::engine::download;
+use crate::metrics;
 use crate::{engine::USER_AGENT, util::SquishError};
 
 use std::fs;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
+use std::time::Duration;
 
-use libsquish::SyncResult;
+use libsquish::{CachedRootfs, SyncResult};
+use nix::sys::utsname::uname;
+use tokio::time::sleep;
+use warp::hyper::body::Bytes;
 use yaml_rust::{Yaml, YamlLoader};
 
-/// The current version of Alpine that this squishd knows about.
+/// The current version of Alpine that this squishd knows about. This is the
+/// default for the `alpine.version` config option.
 pub const VERSION: &str = "3.14";
-/// The architecture of Alpine that this squishd knows about. Maybe this will
-/// support ARM etc. in the future.
-pub const ARCH: &str = "x86_64";
+/// Every architecture that Alpine publishes a minirootfs for.
+pub const ARCHES: &[&str] = &[
+    "x86_64",
+    "x86",
+    "aarch64",
+    "armhf",
+    "armv7",
+    "ppc64le",
+    "s390x",
+    "riscv64",
+    "loongarch64",
+];
+
+lazy_static! {
+    static ref HOST_ARCH: String = detect_host_arch();
+}
+
+/// The host's architecture, in Alpine's naming. This is the default for the
+/// `alpine.arch` config option.
+pub fn host_arch() -> &'static str {
+    &HOST_ARCH
+}
+
+fn detect_host_arch() -> String {
+    let machine = match uname() {
+        Ok(uts) => uts.machine().to_string_lossy().into_owned(),
+        Err(_) => std::env::consts::ARCH.to_string(),
+    };
+    match machine.as_str() {
+        "i386" | "i486" | "i586" | "i686" => "x86",
+        "arm64" => "aarch64",
+        "armv7l" | "armv8l" => "armv7",
+        "armv6l" => "armhf",
+        other => other,
+    }
+    .to_string()
+}
+
+/// Checks that containers of the given architecture can run here. Alpine has
+/// to publish a rootfs for it, and it has to run natively on the host: 32-bit
+/// x86 and ARM run on their 64-bit counterparts, but nothing is emulated.
+pub fn check_arch(arch: &str) -> Result<(), SquishError> {
+    if !ARCHES.contains(&arch) {
+        return Err(SquishError::NoArtefactForArch(
+            "alpine rootfs".to_string(),
+            arch.to_string(),
+        ));
+    }
+    let host = host_arch();
+    let native = arch == host
+        || matches!(
+            (host, arch),
+            ("x86_64", "x86") | ("aarch64", "armv7") | ("aarch64", "armhf") | ("armv7", "armhf")
+        );
+    if native {
+        Ok(())
+    } else {
+        Err(SquishError::CrossArch(arch.to_string(), host.to_string()))
+    }
+}
+
+/// Checks that an Alpine version is a release branch, eg. `3.14`, or `edge`.
+/// Versions end up in cache paths and mirror URLs, so nothing else is let
+/// anywhere near them.
+pub fn check_version(version: &str) -> Result<(), SquishError> {
+    let is_number = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    let valid = version == "edge"
+        || matches!(version.split_once('.'), Some((major, minor)) if is_number(major) && is_number(minor));
+    if valid {
+        Ok(())
+    } else {
+        Err(SquishError::InvalidAlpineVersion(version.to_string()))
+    }
+}
 
 /// The rootfs directory. This is the directory that Alpine rootfs images are
-/// cached in.
-pub fn rootfs_directory() -> &'static str {
-    "cache/alpine/rootfs"
+/// cached in, under the cache directory.
+pub fn rootfs_directory() -> String {
+    format!("{}/alpine/rootfs", config::get().cache_dir.display())
 }
 
 /// The path to the current rootfs tarball. This is
@@ -37,35 +117,147 @@ pub fn current_rootfs(version: &str, arch: &str) -> String {
     format!("{}/alpine-rootfs-{}-{}", rootfs_directory(), version, arch)
 }
 
-/// The base URL to download Alpine rootfs images from.
-/// TODO: Use a mirror list properly
-pub fn base_url(version: &str, arch: &str) -> String {
+/// The file that marks a rootfs as completely extracted and set up. A rootfs
+/// without one is left over from an extraction that never finished.
+pub fn complete_marker(rootfs: &str) -> String {
+    format!("{}.complete", rootfs)
+}
+
+/// Whether the given rootfs is in the cache and ready to use.
+pub fn is_cached(version: &str, arch: &str) -> bool {
+    Path::new(&complete_marker(&current_rootfs(version, arch))).exists()
+}
+
+/// Every Alpine rootfs that's been extracted into the cache.
+pub fn cached_rootfses() -> Vec<CachedRootfs> {
+    let entries = match fs::read_dir(rootfs_directory()) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut cached: Vec<CachedRootfs> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            if !entry.file_type().ok()?.is_dir() {
+                return None;
+            }
+            let name = entry.file_name().into_string().ok()?;
+            let (version, arch) = name.strip_prefix("alpine-rootfs-")?.rsplit_once('-')?;
+            if !is_cached(version, arch) {
+                return None;
+            }
+            Some(CachedRootfs {
+                version: version.to_string(),
+                arch: arch.to_string(),
+            })
+        })
+        .collect();
+    cached.sort_by(|a, b| (&a.version, &a.arch).cmp(&(&b.version, &b.arch)));
+    cached
+}
+
+/// The mirrors that squishd knows about. This is the default for the
+/// `alpine.mirrors` config option.
+pub const MIRRORS: &[&str] = &[
+    "https://cz.alpinelinux.org/alpine",
+    "https://dl-cdn.alpinelinux.org/alpine",
+];
+
+/// The URL of the releases directory of the given mirror. Mirrors are laid
+/// out the same, so this is eg. `<mirror>/v3.14/releases/x86_64`, or
+/// `<mirror>/edge/releases/x86_64` for edge.
+pub fn base_url(mirror: &str, version: &str, arch: &str) -> String {
+    let branch = if version == "edge" {
+        version.to_string()
+    } else {
+        format!("v{}", version)
+    };
     format!(
-        "https://cz.alpinelinux.org/alpine/v{}/releases/{}",
-        version, arch
+        "{}/{}/releases/{}",
+        mirror.trim_end_matches('/'),
+        branch,
+        arch
     )
 }
 
 /// Download the base Alpine rootfs image. This will download and cache the
-/// rootfs image from a mirror (based on `base_url()`).
+/// rootfs image from the first of the configured mirrors that has it, trying
+/// each one a few times before moving on to the next. Concurrent calls for the
+/// same rootfs wait on a single download.
 pub async fn download_base_image(version: &str, arch: &str) -> SyncResult<()> {
-    if Path::new(&current_rootfs_tarball(version, arch)).exists() {
-        info!("rootfs tarball already exists, not downloading again");
+    check_version(version)?;
+    if is_cached(version, arch) {
+        info!("rootfs already exists, not downloading again");
+        metrics::cache_lookup("alpine", true);
         return Ok(());
     }
-    let manifest_url = format!("{}/latest-releases.yaml", base_url(version, arch));
+    let _guard = download::lock(&format!("alpine-{}-{}", version, arch)).await;
+    if is_cached(version, arch) {
+        // Someone else just downloaded it
+        metrics::cache_lookup("alpine", true);
+        return Ok(());
+    }
+    metrics::cache_lookup("alpine", false);
+
+    // A tarball without a rootfs means that an earlier extraction never
+    // finished, so there's no need to download it again
+    if let Some(tarball) = cached_tarball(version, arch) {
+        match install_rootfs(tarball, version, arch).await {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!("couldn't extract cached alpine tarball: {}", e),
+        }
+    }
+
+    let config = config::get();
+    if config.offline {
+        return Err(Box::new(SquishError::NotCached(format!(
+            "alpine {} ({})",
+            version, arch
+        ))));
+    }
+
+    let alpine = &config.alpine;
+    let attempts = alpine.mirror_retries + 1;
+    let mut last_error = None;
+    for mirror in &alpine.mirrors {
+        for attempt in 1..=attempts {
+            if attempt > 1 {
+                sleep(RETRY_DELAY).await;
+            }
+            match download_from_mirror(mirror, version, arch).await {
+                Ok(tarball) => return install_rootfs(tarball, version, arch).await,
+                Err(e) => {
+                    warn!(
+                        "couldn't download alpine {} ({}) from {} (attempt {}/{}): {}",
+                        version, arch, mirror, attempt, attempts, e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+    match last_error {
+        Some(e) => Err(format!(
+            "couldn't download alpine {} ({}) from any mirror, last error: {}",
+            version, arch, e
+        )
+        .into()),
+        None => Err("no alpine mirrors are configured".into()),
+    }
+}
+
+/// How long to wait before trying a mirror again.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+async fn download_from_mirror(mirror: &str, version: &str, arch: &str) -> SyncResult<Vec<u8>> {
+    let manifest_url = format!("{}/latest-releases.yaml", base_url(mirror, version, arch));
     debug!("downloading alpine minirootfs from {}", &manifest_url);
-    let manifest_text = reqwest::Client::builder()
-        .user_agent(USER_AGENT)
-        .build()?
-        .get(manifest_url)
-        .send()
-        .await?
-        .text()
-        .await?;
+    let manifest_bytes = fetch(&manifest_url).await?;
+    let manifest_text = String::from_utf8(manifest_bytes)?;
 
     let docs = YamlLoader::load_from_str(manifest_text.as_str())?;
-    let manifest = &docs[0];
+    let manifest = docs
+        .first()
+        .ok_or_else(|| Box::new(SquishError::AlpineManifestInvalid))?;
     if let Some(vec) = manifest.as_vec() {
         let maybe_rootfs_manifest = vec.iter().find(|yaml| {
             matches!(
@@ -74,10 +266,8 @@ pub async fn download_base_image(version: &str, arch: &str) -> SyncResult<()> {
             )
         });
         if let Some(rootfs_manifest) = maybe_rootfs_manifest {
-            info!("found alpine minirootfs! downloading...");
-            let tarball = download_rootfs(rootfs_manifest, version, arch).await?;
-            extract_tarball(tarball, current_rootfs(version, arch))?;
-            setup_rootfs(&current_rootfs(version, arch))
+            info!("found alpine minirootfs on {}! downloading...", mirror);
+            download_rootfs(rootfs_manifest, mirror, version, arch).await
         } else {
             error!(
                 "expected alpine minirootfs in manifest, but manifest was\n{}",
@@ -90,33 +280,246 @@ pub async fn download_base_image(version: &str, arch: &str) -> SyncResult<()> {
     }
 }
 
-async fn download_rootfs(rootfs_manifest: &Yaml, version: &str, arch: &str) -> SyncResult<String> {
+async fn download_rootfs(
+    rootfs_manifest: &Yaml,
+    mirror: &str,
+    version: &str,
+    arch: &str,
+) -> SyncResult<Vec<u8>> {
     match rootfs_manifest["file"].as_str() {
         Some(rootfs_filename) => {
+            if !checksum::is_plain_file_name(rootfs_filename) {
+                error!(
+                    "alpine manifest on {} names a file outside its directory: {}",
+                    mirror, rootfs_filename
+                );
+                return Err(Box::new(SquishError::AlpineManifestInvalid));
+            }
             // minirootfs is a ~3MB tarball, so we can afford to hold
             // it all in memory.
-            let rootfs_url = format!("{}/{}", base_url(version, arch), rootfs_filename);
-
-            let download_response = reqwest::get(rootfs_url).await?;
-            let rootfs_bytes = download_response.bytes().await?;
+            let rootfs_url = format!("{}/{}", base_url(mirror, version, arch), rootfs_filename);
+            let digest = manifest_digest(rootfs_manifest)?;
+            let rootfs_bytes = fetch(&rootfs_url).await?;
+            checksum::verify_download(rootfs_filename, &rootfs_bytes, &digest)?;
 
             let output_path = current_rootfs_tarball(version, arch);
             debug!("downloading alpine minirootfs into {}", &output_path);
-            fs::create_dir_all(&rootfs_directory())?;
-            let mut output_file = fs::OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&output_path)?;
-            output_file.write_all(&rootfs_bytes)?;
-            Ok(output_path)
+            cache_tarball(&output_path, &rootfs_bytes, &digest)?;
+            Ok(rootfs_bytes)
         }
         None => Err(Box::new(SquishError::AlpineManifestFileMissing)),
     }
 }
 
-fn extract_tarball(path: String, target_path: String) -> SyncResult<()> {
-    info!("extracting alpine rootfs from {} to {}", path, target_path);
-    let tarball = fs::File::open(path)?;
+/// The digest that a manifest gives for its file, preferring the stronger one.
+fn manifest_digest(rootfs_manifest: &Yaml) -> Result<Digest, SquishError> {
+    if let Some(sha512) = rootfs_manifest["sha512"].as_str() {
+        Ok(Digest::Sha512(sha512.to_string()))
+    } else if let Some(sha256) = rootfs_manifest["sha256"].as_str() {
+        Ok(Digest::Sha256(sha256.to_string()))
+    } else {
+        Err(SquishError::AlpineManifestChecksumMissing)
+    }
+}
+
+/// Fetches a file from a mirror. `file://` URLs are read straight off disk,
+/// for mirrors that are local directories laid out like the real thing.
+async fn fetch(url: &str) -> SyncResult<Vec<u8>> {
+    let bytes = match url.strip_prefix("file://") {
+        Some(path) => tokio::fs::read(path).await?,
+        None => reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_secs(
+                config::get().alpine.mirror_timeout_secs,
+            ))
+            .build()?
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec(),
+    };
+    metrics::DOWNLOAD_BYTES
+        .with_label_values(&["alpine"])
+        .inc_by(bytes.len() as u64);
+    Ok(bytes)
+}
+
+/// Writes a verified tarball into the cache. Its digest goes first, so that
+/// a tarball is never cached without the digest it has to match.
+fn cache_tarball(path: &str, tarball: &[u8], digest: &Digest) -> SyncResult<()> {
+    fs::create_dir_all(rootfs_directory())?;
+    checksum::record(Path::new(path), digest)?;
+    download::write_atomically(Path::new(path), tarball, 0o644)?;
+    Ok(())
+}
+
+/// The cached tarball for a rootfs, as long as it still matches its digest.
+fn cached_tarball(version: &str, arch: &str) -> Option<Vec<u8>> {
+    let path = current_rootfs_tarball(version, arch);
+    let tarball = fs::read(&path).ok()?;
+    match checksum::recorded(Path::new(&path)) {
+        Some(digest) if digest.verify(&path, &tarball).is_ok() => Some(tarball),
+        _ => None,
+    }
+}
+
+/// Extracts a tarball into the cache as the given rootfs, on a blocking
+/// thread since that takes a while. See `swap_in`. The caller should hold the
+/// rootfs's download lock.
+async fn install_rootfs(tarball: Vec<u8>, version: &str, arch: &str) -> SyncResult<()> {
+    let (version, arch) = (version.to_string(), arch.to_string());
+    tokio::task::spawn_blocking(move || {
+        let staging = staging_dir()?;
+        info!("extracting alpine rootfs into {}", staging);
+        let result = unpack(&tarball, &staging).and_then(|_| swap_in(&staging, &version, &arch));
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&staging);
+        }
+        result
+    })
+    .await?
+}
+
+/// A fresh directory to extract a rootfs into before it's moved into place.
+fn staging_dir() -> SyncResult<String> {
+    fs::create_dir_all(rootfs_directory())?;
+    let staging = download::temp_path(Path::new(&rootfs_directory()), "alpine-rootfs");
+    Ok(staging.display().to_string())
+}
+
+/// Sets up an extracted rootfs, moves it into place and marks it complete.
+/// Nothing is marked complete until it's ready to use, so a crash partway
+/// through leaves nothing behind that looks usable. A rootfs that's being
+/// replaced is only moved aside, as running containers still have it mounted.
+fn swap_in(staging: &str, version: &str, arch: &str) -> SyncResult<()> {
+    setup_rootfs(staging)?;
+    let rootfs = current_rootfs(version, arch);
+    let marker = complete_marker(&rootfs);
+    let _ = fs::remove_file(&marker);
+    if Path::new(&rootfs).exists() {
+        let retired = download::retire(Path::new(&rootfs))?;
+        info!(
+            "replacing alpine rootfs {}, the old one is in {} until squishd restarts",
+            rootfs,
+            retired.display()
+        );
+    }
+    fs::rename(staging, &rootfs)?;
+    download::write_atomically(Path::new(&marker), b"", 0o644)?;
+    Ok(())
+}
+
+/// Imports a minirootfs tarball from elsewhere, eg. one copied onto a machine
+/// without network access, into the cache as if it had been downloaded. The
+/// version comes from the rootfs itself unless one is given. The architecture
+/// can't be told from the rootfs, so it has to be given, or come from the
+/// tarball's name (`alpine-minirootfs-<version>-<arch>.tar.gz`). Returns the
+/// version and architecture it was imported as.
+pub async fn import_rootfs(
+    tarball: Bytes,
+    file_name: Option<&str>,
+    version: Option<&str>,
+    arch: Option<&str>,
+) -> SyncResult<CachedRootfs> {
+    if let Some(version) = version {
+        check_version(version)?;
+    }
+    let arch = match arch.or_else(|| arch_from_file_name(file_name?)) {
+        Some(arch) if !ARCHES.contains(&arch) => {
+            return Err(Box::new(SquishError::InvalidImport(format!(
+                "alpine doesn't publish rootfses for {}",
+                arch
+            ))))
+        }
+        Some(arch) => arch.to_string(),
+        None => {
+            return Err(Box::new(SquishError::InvalidImport(
+                "couldn't tell the architecture from the file name, pass one explicitly"
+                    .to_string(),
+            )))
+        }
+    };
+
+    // Extract somewhere out of the way first, so that a bad tarball doesn't
+    // clobber what's already cached. Extracting and installing a big tarball
+    // takes a while, so both happen on blocking threads.
+    let staging = staging_dir()?;
+    let result = {
+        let (tarball, staging) = (tarball.clone(), staging.clone());
+        let version = version.map(String::from);
+        tokio::task::spawn_blocking(move || stage_import(&tarball, &staging, version.as_deref()))
+            .await?
+    };
+    let version = match result {
+        Ok(version) => version,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+
+    let _guard = download::lock(&format!("alpine-{}-{}", version, arch)).await;
+    let result = {
+        let (staging, version, arch) = (staging.clone(), version.clone(), arch.clone());
+        tokio::task::spawn_blocking(move || {
+            let tarball_path = current_rootfs_tarball(&version, &arch);
+            cache_tarball(&tarball_path, &tarball, &Digest::sha512_of(&tarball))
+                .and_then(|_| swap_in(&staging, &version, &arch))
+        })
+        .await?
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+    info!(
+        "imported alpine {} ({}) into {}",
+        version,
+        arch,
+        current_rootfs(&version, &arch)
+    );
+    Ok(CachedRootfs { version, arch })
+}
+
+/// Extracts an imported tarball into `staging`, and works out which version
+/// it is unless one is given.
+fn stage_import(tarball: &[u8], staging: &str, version: Option<&str>) -> SyncResult<String> {
+    unpack(tarball, staging)
+        .map_err(|e| SquishError::InvalidImport(format!("couldn't extract tarball: {}", e)))?;
+    let release = fs::read_to_string(format!("{}/etc/alpine-release", staging))
+        .map_err(|_| SquishError::InvalidImport("not an alpine minirootfs tarball".to_string()))?;
+    match version {
+        Some(version) => Ok(version.to_string()),
+        // Releases look like 3.14.2, but rootfses are cached by branch
+        None => {
+            let branch = release
+                .trim()
+                .splitn(3, '.')
+                .take(2)
+                .collect::<Vec<_>>()
+                .join(".");
+            check_version(&branch).map_err(|_| {
+                SquishError::InvalidImport(format!(
+                    "couldn't tell the version from alpine-release ({}), pass one explicitly",
+                    release.trim()
+                ))
+            })?;
+            Ok(branch)
+        }
+    }
+}
+
+fn arch_from_file_name(file_name: &str) -> Option<&str> {
+    let name = file_name
+        .strip_prefix("alpine-minirootfs-")?
+        .strip_suffix(".tar.gz")?;
+    Some(name.rsplit_once('-')?.1)
+}
+
+fn unpack(tarball: &[u8], target_path: &str) -> SyncResult<()> {
     let tar = flate2::read::GzDecoder::new(tarball);
     let mut archive = tar::Archive::new(tar);
     archive.unpack(target_path)?;
@@ -0,0 +1,97 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{access, AccessFlags};
+
+/// Finds the cgroup v2 directory that squishd itself is running in, provided
+/// that it's been delegated to us (ie. we're allowed to create child cgroups
+/// and move processes into them). Containers get their own cgroups under this
+/// directory. Returns `None` on cgroup v1-only hosts, or if we weren't
+/// delegated a cgroup.
+pub fn delegated_root() -> Option<PathBuf> {
+    let mount = cgroup2_mount()?;
+    let own_cgroup = fs::read_to_string("/proc/self/cgroup").ok()?;
+    // The cgroup v2 hierarchy is always listed with a hierarchy id of 0
+    let relative = own_cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))?
+        .trim_start_matches('/');
+    let root = mount.join(relative);
+    let writable = |path: &Path| access(path, AccessFlags::W_OK).is_ok();
+    if writable(&root) && writable(&root.join("cgroup.procs")) {
+        Some(root)
+    } else {
+        None
+    }
+}
+
+/// Finds where the cgroup v2 hierarchy is mounted. This is usually
+/// `/sys/fs/cgroup`, but is eg. `/sys/fs/cgroup/unified` on hybrid hosts.
+fn cgroup2_mount() -> Option<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        // See proc(5): the fields after the " - " separator start with the
+        // filesystem type, and the mount point is the fifth field before it.
+        let (mount, fs) = line.split_once(" - ")?;
+        if fs.split(' ').next()? == "cgroup2" {
+            mount.split(' ').nth(4).map(PathBuf::from)
+        } else {
+            None
+        }
+    })
+}
+
+/// Creates a cgroup for the container with the given id, returning its path.
+/// Returns `None` if no delegated cgroup is available, in which case the
+/// container simply runs in squishd's own cgroup.
+pub fn create(id: &str) -> Option<String> {
+    let path = delegated_root()?.join(format!("squish-{}", id));
+    match fs::create_dir(&path) {
+        Ok(_) => Some(path.display().to_string()),
+        Err(e) => {
+            warn!("couldn't create cgroup {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Freezes or thaws every process in the given cgroup.
+pub fn set_frozen(cgroup: &str, frozen: bool) -> io::Result<()> {
+    fs::write(
+        format!("{}/cgroup.freeze", cgroup),
+        if frozen { "1" } else { "0" },
+    )
+}
+
+/// SIGKILLs every process in the given cgroup. Needs Linux 5.14 or later.
+pub fn kill(cgroup: &str) -> io::Result<()> {
+    fs::write(format!("{}/cgroup.kill", cgroup), "1")
+}
+
+/// Removes a container's cgroup. This only works once every process in it has
+/// exited.
+pub fn remove(cgroup: &str) -> io::Result<()> {
+    match fs::remove_dir(cgroup) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the total CPU time used by a cgroup, in microseconds.
+pub fn cpu_usage_usec(cgroup: &str) -> Option<u64> {
+    let stat = fs::read_to_string(format!("{}/cpu.stat", cgroup)).ok()?;
+    stat.lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|usec| usec.trim().parse().ok())
+}
+
+/// Returns the memory used by a cgroup, in bytes. This is only available if
+/// the memory controller is enabled for it.
+pub fn memory_current(cgroup: &str) -> Option<u64> {
+    fs::read_to_string(format!("{}/memory.current", cgroup))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
@@ -0,0 +1,233 @@
+use crate::config;
+use crate::engine::{alpine, download, slirp};
+use crate::util::SquishError;
+
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libsquish::{VerifiedFile, VerifyStatus};
+
+/// The expected digest of a file, as hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Digest {
+    Sha256(String),
+    Sha512(String),
+}
+
+impl Digest {
+    pub fn sha256_of(bytes: &[u8]) -> Digest {
+        Digest::Sha256(hex::encode(hmac_sha256::Hash::hash(bytes)))
+    }
+
+    pub fn sha512_of(bytes: &[u8]) -> Digest {
+        Digest::Sha512(hex::encode(hmac_sha512::Hash::hash(bytes)))
+    }
+
+    /// The digest of the given bytes, using the same algorithm as this one.
+    pub fn of(&self, bytes: &[u8]) -> Digest {
+        match self {
+            Digest::Sha256(_) => Digest::sha256_of(bytes),
+            Digest::Sha512(_) => Digest::sha512_of(bytes),
+        }
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self {
+            Digest::Sha256(_) => "sha256",
+            Digest::Sha512(_) => "sha512",
+        }
+    }
+
+    fn hex(&self) -> &str {
+        match self {
+            Digest::Sha256(hex) | Digest::Sha512(hex) => hex,
+        }
+    }
+
+    /// Checks that `bytes` have this digest. `what` names them in the error.
+    pub fn verify(&self, what: &str, bytes: &[u8]) -> Result<(), SquishError> {
+        let actual = self.of(bytes);
+        if actual.hex().eq_ignore_ascii_case(self.hex()) {
+            Ok(())
+        } else {
+            Err(SquishError::ChecksumMismatch(
+                what.to_string(),
+                self.to_string(),
+                actual.to_string(),
+            ))
+        }
+    }
+}
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm(), self.hex())
+    }
+}
+
+/// Checks freshly-downloaded bytes against their expected digest before
+/// anything is done with them. Bytes that don't match are quarantined rather
+/// than cached.
+pub fn verify_download(name: &str, bytes: &[u8], digest: &Digest) -> Result<(), SquishError> {
+    let error = match digest.verify(name, bytes) {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+    };
+    let quarantined = quarantine_path(name);
+    match fs::create_dir_all(quarantine_dir()).and_then(|_| fs::write(&quarantined, bytes)) {
+        Ok(()) => warn!(
+            "quarantined bad download of {} as {}",
+            name,
+            quarantined.display()
+        ),
+        Err(e) => warn!("couldn't quarantine bad download of {}: {}", name, e),
+    }
+    Err(error)
+}
+
+/// Where files that failed verification are moved to, under the cache
+/// directory. Nothing ever uses them again; they're only kept for inspection.
+pub fn quarantine_dir() -> PathBuf {
+    config::get().cache_dir.join("quarantine")
+}
+
+fn quarantine_path(name: &str) -> PathBuf {
+    // Names can come from a mirror, which mustn't get to write anywhere else
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| is_plain_file_name(name))
+        .unwrap_or_else(|| "unnamed".to_string());
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default();
+    quarantine_dir().join(format!("{}.{}", name, now))
+}
+
+/// Whether a file name from somewhere untrusted, eg. a mirror's manifest,
+/// names a file rather than a path that could lead out of its directory.
+pub fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && !name.contains('/') && !name.contains("..")
+}
+
+/// Moves a cached file that failed verification into quarantine, along with
+/// its recorded digest. Returns where it went.
+fn quarantine(path: &Path) -> io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    fs::create_dir_all(quarantine_dir())?;
+    let quarantined = quarantine_path(&name);
+    fs::rename(path, &quarantined)?;
+    for algorithm in ALGORITHMS {
+        let _ = fs::remove_file(digest_path(path, algorithm));
+    }
+    Ok(quarantined)
+}
+
+/// The algorithms that digests can be recorded with.
+const ALGORITHMS: &[&str] = &["sha256", "sha512"];
+
+fn digest_path(path: &Path, algorithm: &str) -> PathBuf {
+    let mut digest_path = path.as_os_str().to_owned();
+    digest_path.push(".");
+    digest_path.push(algorithm);
+    PathBuf::from(digest_path)
+}
+
+/// Records the digest of a cached file next to it, eg. `slirp4netns.sha256`,
+/// so that `verify_cache` can check it again later. Any digest recorded with
+/// another algorithm is removed, as `recorded` would otherwise prefer it.
+pub fn record(path: &Path, digest: &Digest) -> io::Result<()> {
+    fs::write(digest_path(path, digest.algorithm()), digest.hex())?;
+    for algorithm in ALGORITHMS {
+        if *algorithm == digest.algorithm() {
+            continue;
+        }
+        match fs::remove_file(digest_path(path, algorithm)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// The digest recorded for a cached file, if any.
+pub fn recorded(path: &Path) -> Option<Digest> {
+    if let Ok(hex) = fs::read_to_string(digest_path(path, "sha512")) {
+        return Some(Digest::Sha512(hex.trim().to_string()));
+    }
+    if let Ok(hex) = fs::read_to_string(digest_path(path, "sha256")) {
+        return Some(Digest::Sha256(hex.trim().to_string()));
+    }
+    None
+}
+
+/// Re-checks every cached Alpine tarball and slirp4netns binary against the
+/// digest recorded when it was cached. Files that don't match are quarantined,
+/// along with the rootfs extracted from a bad tarball, so that they're
+/// downloaded afresh when next needed.
+pub fn verify_cache() -> Vec<VerifiedFile> {
+    let mut files: Vec<PathBuf> = fs::read_dir(alpine::rootfs_directory())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.to_string_lossy().ends_with(".tar.gz"))
+        .collect();
+    files.sort();
+    let slirp = slirp::cached_binary();
+    if slirp.exists() {
+        files.push(slirp);
+    }
+    files.iter().map(|path| verify_file(path)).collect()
+}
+
+fn verify_file(path: &Path) -> VerifiedFile {
+    let mut verified = VerifiedFile {
+        path: path.display().to_string(),
+        status: VerifyStatus::Ok,
+        quarantined: None,
+    };
+    let expected = match recorded(path) {
+        Some(expected) => expected,
+        None => {
+            verified.status = VerifyStatus::Unrecorded;
+            return verified;
+        }
+    };
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("couldn't read {}: {}", path.display(), e);
+            verified.status = VerifyStatus::Unreadable;
+            return verified;
+        }
+    };
+    if let Err(e) = expected.verify(&verified.path, &bytes) {
+        warn!("{}", e);
+        verified.status = VerifyStatus::Mismatch;
+        match quarantine(path) {
+            Ok(quarantined) => {
+                verified.quarantined = Some(quarantined.display().to_string());
+            }
+            Err(e) => warn!("couldn't quarantine {}: {}", path.display(), e),
+        }
+        // Whatever was extracted from a bad tarball can't be trusted either,
+        // but running containers may still have it mounted
+        if let Some(rootfs) = verified.path.strip_suffix(".tar.gz") {
+            let _ = fs::remove_file(alpine::complete_marker(rootfs));
+            if Path::new(rootfs).exists() {
+                if let Err(e) = download::retire(Path::new(rootfs)) {
+                    warn!("couldn't move {} aside: {}", rootfs, e);
+                }
+            }
+        }
+    }
+    verified
+}
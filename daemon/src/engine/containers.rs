@@ -1,20 +1,53 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::io::OwnedFd;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use crate::auth::Caller;
+use crate::config;
+use crate::engine::{cgroup, journal, pidfd, procfs, SpawnedContainer};
+use crate::metrics;
+use crate::util;
+use crate::util::SquishError;
+
 use haikunator::Haikunator;
-use libsquish::Result;
+use libsquish::event::{ContainerEvent, EventKind};
+use libsquish::squishfile::Squishfile;
+use libsquish::{
+    ContainerStatus, ExitStatus, Result, RunningContainer, StoppedContainer, SyncResult,
+};
+use nix::errno::Errno;
 use nix::sys::signal;
-use nix::sys::signal::kill;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinSet;
 use tokio::time::sleep;
 
+/// How long a container gets to exit after its stop signal before it's killed,
+/// unless its squishfile says otherwise.
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a container to die after SIGKILL before giving up on
+/// it and cleaning up anyway.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many events can be buffered for a slow `/events` subscriber before it
+/// starts missing them.
+const EVENT_BUFFER_SIZE: usize = 256;
+
 /// A squish container. A container is basically just a tracked pid, that has a
 /// hexadecimal id and a name attached to it. Containers also contain a pid for
 /// their respective slirp4netns instances, as well as a timestamp for when
-/// they were created.
+/// they were created. Once a container's process has exited, its exit status
+/// is kept around until the container is stopped. The resolved squishfile the
+/// container was created from is kept as well, so that it can be restarted.
 #[derive(Debug, Clone)]
 pub struct Container {
     pub name: String,
@@ -22,6 +55,39 @@ pub struct Container {
     pub slirp_pid: nix::unistd::Pid,
     pub id: String,
     pub created_at: u128,
+    pub status: ContainerStatus,
+    pub exit: Option<ExitStatus>,
+    pub squishfile: Squishfile,
+    pub cgroup: Option<String>,
+    /// The uid of the user that created the container.
+    pub owner: u32,
+}
+
+impl Container {
+    /// The signal sent to the container when it's stopped.
+    pub fn stop_signal(&self) -> Signal {
+        stop_signal(&self.squishfile).unwrap_or(Signal::SIGTERM)
+    }
+
+    /// How long the container gets to exit after its stop signal.
+    pub fn stop_timeout(&self) -> Duration {
+        self.squishfile
+            .run()
+            .stop_timeout()
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_STOP_TIMEOUT)
+    }
+
+    /// Builds a lifecycle event of the given kind for this container.
+    pub fn event(&self, kind: EventKind) -> ContainerEvent {
+        ContainerEvent::new(
+            kind,
+            self.id.as_str(),
+            self.name.as_str(),
+            Some(self.pid.as_raw()),
+            self.owner,
+        )
+    }
 }
 
 impl From<&Container> for libsquish::RunningContainer {
@@ -30,6 +96,68 @@ impl From<&Container> for libsquish::RunningContainer {
             id: container.id.clone(),
             name: container.name.clone(),
             pid: container.pid.into(),
+            owner: container.owner,
+            status: container.status,
+            exit: container.exit,
+            labels: container.squishfile.labels().clone(),
+        }
+    }
+}
+
+/// Picks out containers for bulk operations. Filters are written as
+/// `key=value`: `name=<prefix>`, `status=<status>`, and `label=<key>` or
+/// `label=<key>=<value>`.
+#[derive(Debug)]
+pub enum ContainerFilter {
+    /// Containers whose names start with the given prefix.
+    Name(String),
+    Status(ContainerStatus),
+    /// Containers with the given label, optionally set to the given value.
+    Label(String, Option<String>),
+}
+
+impl FromStr for ContainerFilter {
+    type Err = SquishError;
+
+    fn from_str(filter: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || SquishError::InvalidFilter(filter.to_string());
+        let (key, value) = filter.split_once('=').ok_or_else(invalid)?;
+        if value.is_empty() {
+            return Err(invalid());
+        }
+        match key {
+            "name" => Ok(ContainerFilter::Name(value.to_string())),
+            "status" => match value {
+                "running" => Ok(ContainerFilter::Status(ContainerStatus::Running)),
+                "paused" => Ok(ContainerFilter::Status(ContainerStatus::Paused)),
+                "stopping" => Ok(ContainerFilter::Status(ContainerStatus::Stopping)),
+                "exited" => Ok(ContainerFilter::Status(ContainerStatus::Exited)),
+                _ => Err(invalid()),
+            },
+            "label" => match value.split_once('=') {
+                Some((key, value)) => Ok(ContainerFilter::Label(
+                    key.to_string(),
+                    Some(value.to_string()),
+                )),
+                None => Ok(ContainerFilter::Label(value.to_string(), None)),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl ContainerFilter {
+    fn matches(&self, container: &Container) -> bool {
+        match self {
+            ContainerFilter::Name(prefix) => container.name.starts_with(prefix),
+            ContainerFilter::Status(status) => container.status == *status,
+            ContainerFilter::Label(key, value) => {
+                match (container.squishfile.labels().get(key), value) {
+                    (Some(actual), Some(value)) => actual == value,
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                }
+            }
         }
     }
 }
@@ -37,154 +165,754 @@ impl From<&Container> for libsquish::RunningContainer {
 /// The global state of the daemon. To avoid constant locking, this is kept
 /// fairly small. It contains a mapping from container ids to `Container`
 /// structs, as well as a mapping from container pids to container ids. This
-/// data can be relied on to always be up to date.
+/// data can be relied on to always be up to date. Lifecycle events are
+/// broadcast to all subscribers of the `/events` stream from here as well.
 #[derive(Debug)]
 pub struct ContainerState {
     id_map: HashMap<String, Container>,
     pid_id_map: HashMap<Pid, String>,
+    /// Names of containers that are still being spawned.
+    reserved_names: HashSet<String>,
+    events: broadcast::Sender<ContainerEvent>,
 }
 
 impl ContainerState {
     pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUFFER_SIZE);
         ContainerState {
             id_map: HashMap::new(),
             pid_id_map: HashMap::new(),
+            reserved_names: HashSet::new(),
+            events,
+        }
+    }
+
+    /// Subscribe to the lifecycle events of all containers.
+    pub fn subscribe(&self) -> broadcast::Receiver<ContainerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Broadcast an event to all current subscribers. Events are dropped if
+    /// nobody is listening.
+    pub fn emit(&self, event: ContainerEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Broadcast an event of the given kind for a container.
+    fn emit_for(&self, container: &Container, kind: EventKind) {
+        self.emit(container.event(kind));
+    }
+
+    /// Returns the container with the given id, if it exists.
+    pub fn get_container(&self, id: &str) -> Option<&Container> {
+        self.id_map.get(id)
+    }
+
+    /// Reserves names for `count` new containers, and generates an id for
+    /// each. A requested name is used as-is for a single container, and
+    /// suffixed with each replica's index for several; otherwise names are
+    /// generated. Names stay reserved until their containers are added, or
+    /// until they're released.
+    pub fn reserve_names(
+        &mut self,
+        name: Option<&str>,
+        count: usize,
+    ) -> std::result::Result<Vec<(String, String)>, SquishError> {
+        let names: Vec<String> = match name {
+            Some(name) if count == 1 => vec![name.to_string()],
+            Some(name) => (0..count).map(|i| format!("{}-{}", name, i)).collect(),
+            None => vec![],
+        };
+        for name in &names {
+            if !valid_name(name) {
+                return Err(SquishError::InvalidName(name.clone()));
+            }
+            if self.name_in_use(name) {
+                return Err(SquishError::NameInUse(name.clone()));
+            }
+        }
+
+        let mut reserved = vec![];
+        for i in 0..count {
+            let name = match names.get(i) {
+                Some(name) => name.clone(),
+                None => self.generate_name(),
+            };
+            self.reserved_names.insert(name.clone());
+            reserved.push((self.generate_id(), name));
         }
+        Ok(reserved)
     }
 
-    /// Generates a (id, name) tuple. The id is a SHA256 hash of the name.
-    pub fn generate_id() -> (String, String) {
+    /// Releases names reserved for containers that were never added.
+    pub fn release_names<'a>(&mut self, names: impl IntoIterator<Item = &'a str>) {
+        for name in names {
+            self.reserved_names.remove(name);
+        }
+    }
+
+    /// Whether a container already has, or is about to have, the given name.
+    /// Names can't clash with ids either, so that a reference to one is never
+    /// mistaken for the other.
+    fn name_in_use(&self, name: &str) -> bool {
+        self.reserved_names.contains(name)
+            || self.id_map.contains_key(name)
+            || self.id_map.values().any(|container| container.name == name)
+    }
+
+    /// Generates a random, unused 256-bit container id.
+    fn generate_id(&self) -> String {
+        loop {
+            let id = hex::encode(rand::random::<[u8; 32]>());
+            if !self.name_in_use(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Generates an unused name, eg. `patient-queen-2928`.
+    fn generate_name(&self) -> String {
         let haiku = Haikunator::default();
-        let name = haiku.haikunate();
-        let hash = hmac_sha256::Hash::hash(name.as_bytes());
-        let id = hex::encode(hash);
-        (id, name)
+        loop {
+            let name = haiku.haikunate();
+            if !self.name_in_use(&name) {
+                return name;
+            }
+        }
     }
 
     /// Add a container to the global container state.
     pub fn add_container(
         &mut self,
-        pid: nix::unistd::Pid,
-        slirp_pid: nix::unistd::Pid,
+        spawned: SpawnedContainer,
         id: &str,
         name: String,
+        squishfile: Squishfile,
+        owner: u32,
     ) -> Result<()> {
+        self.reserved_names.remove(&name);
         self.id_map.insert(
             id.to_string(),
             Container {
                 name,
                 id: id.to_string(),
-                pid,
-                slirp_pid,
+                pid: spawned.pid,
+                slirp_pid: spawned.slirp_pid,
                 created_at: libsquish::now()?,
+                status: ContainerStatus::Running,
+                exit: None,
+                squishfile,
+                cgroup: spawned.cgroup,
+                owner,
             },
         );
-        self.pid_id_map.insert(pid, id.to_string());
+        self.pid_id_map.insert(spawned.pid, id.to_string());
         Ok(())
     }
 
-    /// Remove a container or set of containers based on "fuzzy" matching of
-    /// container names or ids. This partially matches the container name or id
-    /// based on starting characters. That is, a container is removed if its
-    /// name or its id starts with the partial value passed in. This is not a
-    /// general substring match.
-    pub fn fuzzy_remove_container(&mut self, partial_id_or_name: &str) -> Result<Vec<String>> {
-        let matches: Vec<&Container> = (&self.id_map)
-            .iter()
-            .filter(|(id, container)| {
-                id.starts_with(partial_id_or_name) || container.name.starts_with(partial_id_or_name)
-            })
-            .map(|(_id, container)| container)
+    /// Swap a restarted container's freshly-spawned processes in for its old,
+    /// exited ones. Returns `false` if the container no longer exists.
+    fn replace_container_processes(&mut self, id: &str, spawned: SpawnedContainer) -> bool {
+        match self.id_map.get_mut(id) {
+            Some(container) => {
+                self.pid_id_map.remove(&container.pid);
+                self.pid_id_map.insert(spawned.pid, id.to_string());
+                container.pid = spawned.pid;
+                container.slirp_pid = spawned.slirp_pid;
+                container.cgroup = spawned.cgroup;
+                container.status = ContainerStatus::Running;
+                container.exit = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Broadcast the events for a container having just been (re)started.
+    pub fn emit_started(&self, id: &str) {
+        if let Some(container) = self.id_map.get(id) {
+            self.emit_for(container, EventKind::Start);
+            for port in container.squishfile.ports() {
+                self.emit_for(
+                    container,
+                    EventKind::PortForwardAdded {
+                        host: *port.host(),
+                        container: *port.container(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// The containers that the given caller is allowed to see.
+    pub fn visible_to(&self, caller: Caller) -> impl Iterator<Item = &Container> {
+        self.id_map
+            .values()
+            .filter(move |container| caller.can_access(container.owner))
+    }
+
+    /// Resolves a reference to a single container that the caller can see. An
+    /// exact id or name always wins; otherwise the reference has to be a prefix
+    /// of exactly one container's id or name. An ambiguous reference is an
+    /// error listing every container it could mean, rather than a guess.
+    pub fn resolve(
+        &self,
+        caller: &Caller,
+        reference: &str,
+    ) -> std::result::Result<&Container, SquishError> {
+        if let Some(container) = self
+            .visible_to(*caller)
+            .find(|c| c.id == reference || c.name == reference)
+        {
+            return Ok(container);
+        }
+        let mut matches: Vec<&Container> = self
+            .visible_to(*caller)
+            .filter(|c| c.id.starts_with(reference) || c.name.starts_with(reference))
             .collect();
-        let mut matched_ids = vec![];
-        for container in matches {
-            matched_ids.push(container.id.clone());
+        match matches.len() {
+            0 => Err(SquishError::NoSuchContainer(reference.to_string())),
+            1 => Ok(matches.remove(0)),
+            _ => {
+                matches.sort_by(|a, b| a.name.cmp(&b.name));
+                let candidates = matches
+                    .iter()
+                    .map(|c| format!("{} ({})", &c.id[..7], c.name))
+                    .collect();
+                Err(SquishError::AmbiguousReference(
+                    reference.to_string(),
+                    candidates,
+                ))
+            }
         }
-        self.remove_all_containers(matched_ids.clone())?;
-        Ok(matched_ids)
     }
 
-    /// Remove the container with the given id.
-    pub fn remove_container(&mut self, id: &str) -> Result<()> {
-        self.remove_all_containers(vec![id.to_string()])?;
+    /// Returns the ids of every container the caller can see that matches the
+    /// filter, or of every container the caller can see if there's no filter.
+    /// This is for explicitly-requested bulk operations only; anything acting
+    /// on a single container should go through `resolve`.
+    pub fn select(&self, caller: &Caller, filter: Option<&ContainerFilter>) -> Vec<String> {
+        self.visible_to(*caller)
+            .filter(|container| filter.is_none_or(|filter| filter.matches(container)))
+            .map(|container| container.id.clone())
+            .collect()
+    }
+
+    /// Send a signal to the container with the given id. pid1 passes it on to
+    /// the workload, except for SIGKILL, which takes down the whole container.
+    pub fn signal_container(
+        &self,
+        id: &str,
+        signal: Signal,
+    ) -> std::result::Result<(), SquishError> {
+        let container = match self.id_map.get(id) {
+            Some(container) if container.status != ContainerStatus::Exited => container,
+            // Exited containers' pids may well belong to something else by now
+            _ => return Err(SquishError::ContainerNotRunning(id.to_string())),
+        };
+        info!("sending {} to {}", signal, id);
+        kill(container.pid, signal).map_err(|e| SquishError::GenericError(Box::new(e)))
+    }
+
+    /// Pause the container with the given id, freezing every one of its
+    /// processes. This uses the container's cgroup if it has one, and
+    /// otherwise SIGSTOPs every process in its pid namespace.
+    pub fn pause_container(&mut self, id: &str) -> std::result::Result<(), SquishError> {
+        let container = match self.id_map.get_mut(id) {
+            Some(container) if container.status == ContainerStatus::Running => container,
+            _ => return Err(SquishError::ContainerNotRunning(id.to_string())),
+        };
+        info!("pausing {}", id);
+        freeze_container(container, true)?;
+        container.status = ContainerStatus::Paused;
+        Ok(())
+    }
+
+    /// Resume a paused container.
+    pub fn resume_container(&mut self, id: &str) -> std::result::Result<(), SquishError> {
+        let container = match self.id_map.get_mut(id) {
+            Some(container) if container.status == ContainerStatus::Paused => container,
+            _ => return Err(SquishError::ContainerNotPaused(id.to_string())),
+        };
+        info!("resuming {}", id);
+        freeze_container(container, false)?;
+        container.status = ContainerStatus::Running;
         Ok(())
     }
 
-    /// Remove all containers matching the ids in the list. This will kill the
-    /// container and slirp4netns instances as a side effect.
+    /// Remove all containers matching the ids in the list. This will SIGKILL
+    /// any that are still running and clean up after them without waiting, so
+    /// `stop_container` should be preferred.
     pub fn remove_all_containers(&mut self, ids: Vec<String>) -> Result<()> {
         for id in ids {
             let container = self.id_map.remove(&id);
             if let Some(container) = container {
+                if container.status == ContainerStatus::Exited {
+                    // Already reaped and cleaned up, and its pid may well
+                    // belong to something else by now.
+                    info!("Removing exited container {}...", container.id);
+                    self.emit_for(&container, EventKind::Stop);
+                    continue;
+                }
                 info!("Cleaning and killing {}...", container.id);
                 self.pid_id_map.remove(&container.pid);
-                // TODO: Wait and SIGKILL the container as needed
-                match kill(container.pid, signal::SIGTERM) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Failed to kill container {}: {}", container.id, e);
-                    }
-                }
-                match kill(container.slirp_pid, signal::SIGTERM) {
+                match kill(container.pid, signal::SIGKILL) {
                     Ok(_) => (),
                     Err(e) => {
                         error!("Failed to kill container {}: {}", container.id, e);
                     }
                 }
-                cleanup_container(container.id.as_str())?;
+                kill_slirp(&container);
+                cleanup_container(&container)?;
+                self.emit_for(&container, EventKind::Stop);
             }
         }
         Ok(())
     }
 
-    /// Returns a list of all currently-running containers. This is guaranteed
-    /// to never contain state of currently-stopped containers.
-    pub fn running_containers(&self) -> Vec<libsquish::RunningContainer> {
+    /// Record that a container's process has exited, killing its slirp4netns
+    /// instance and cleaning up its files. The exit status written by pid1 is
+    /// preferred over `fallback`, as it describes the workload itself rather
+    /// than pid1.
+    fn reap_container(&mut self, id: &str, fallback: ExitStatus) -> Result<()> {
+        let exit = read_exit_file(id).unwrap_or(fallback);
+        if let Some(container) = self.id_map.get_mut(id) {
+            self.pid_id_map.remove(&container.pid);
+            container.status = ContainerStatus::Exited;
+            container.exit = Some(exit);
+            let container = container.clone();
+            self.emit_for(
+                &container,
+                EventKind::Exit {
+                    code: exit.code,
+                    signal: exit.signal,
+                },
+            );
+            kill_slirp(&container);
+            cleanup_container(&container)?;
+            metrics::REAPER_CLEANUPS.inc();
+            self.emit_for(&container, EventKind::Reaped);
+        }
+        Ok(())
+    }
+
+    /// Returns how many containers currently have the given status.
+    pub fn count_by_status(&self, status: ContainerStatus) -> usize {
+        self.id_map
+            .values()
+            .filter(|container| container.status == status)
+            .count()
+    }
+
+    /// Returns a list of all containers visible to the caller that haven't
+    /// been stopped yet. This includes containers that have exited on their
+    /// own.
+    pub fn running_containers(&self, caller: &Caller) -> Vec<libsquish::RunningContainer> {
         let mut out = vec![];
-        for v in self.id_map.values() {
+        for v in self.visible_to(*caller) {
             out.push(v.into());
         }
         out
     }
 }
 
-/// A background task for reaping dead containers. This checks the global state
-/// 10 times per second, removing all container pids that no longer exist.
-pub async fn reap_children(state: Arc<Mutex<ContainerState>>) {
+/// Waits until the container with the given id has exited, returning its
+/// exit status. Returns `None` if the container doesn't exist, or is stopped
+/// before it exits on its own.
+pub async fn wait_for_exit(state: &Arc<Mutex<ContainerState>>, id: &str) -> Option<ExitStatus> {
+    // Subscribe before checking, so that an exit can't slip through between
+    // the check and the subscription.
+    let mut receiver = {
+        let container_state = state.lock().unwrap();
+        let receiver = container_state.subscribe();
+        if let Some(exit) = container_state.get_container(id)?.exit {
+            return Some(exit);
+        }
+        receiver
+    };
     loop {
-        sleep(Duration::from_millis(100)).await;
+        match receiver.recv().await {
+            Ok(event) if event.id == id => match event.kind {
+                EventKind::Exit { code, signal } => {
+                    return Some(ExitStatus {
+                        code,
+                        signal,
+                        exited_at: event.timestamp,
+                    })
+                }
+                EventKind::Stop => return None,
+                _ => (),
+            },
+            Ok(_) => (),
+            Err(RecvError::Lagged(_)) => {
+                // We may have missed the exit, so check again
+                let container_state = state.lock().unwrap();
+                if let Some(exit) = container_state.get_container(id)?.exit {
+                    return Some(exit);
+                }
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Parses the stop signal out of a squishfile, if it specifies one.
+pub fn stop_signal(squishfile: &Squishfile) -> std::result::Result<Signal, SquishError> {
+    match squishfile.run().stop_signal() {
+        Some(signal) => util::parse_workload_signal(signal),
+        None => Ok(Signal::SIGTERM),
+    }
+}
+
+/// Leaves every container running, for when squishd shuts down with
+/// `--keep-containers`. Their journals are marked so that the next squishd
+/// doesn't clean them up as orphans while they're still running. Returns how
+/// many were left running.
+pub fn keep_all_containers(state: &Arc<Mutex<ContainerState>>) -> usize {
+    let container_state = state.lock().unwrap();
+    let mut kept = 0;
+    for container in container_state.id_map.values() {
+        if container.status == ContainerStatus::Exited {
+            continue;
+        }
+        if let Err(e) = journal::mark_kept(&container.id) {
+            warn!("couldn't mark {} as kept: {}", container.id, e);
+        }
+        kept += 1;
+    }
+    kept
+}
+
+/// Stops every container, for when squishd shuts down. Returns the containers
+/// that were stopped.
+pub async fn stop_all_containers(state: &Arc<Mutex<ContainerState>>) -> Vec<StoppedContainer> {
+    let ids = state.lock().unwrap().id_map.keys().cloned().collect();
+    stop_containers(state, ids, None).await
+}
+
+/// Stops several containers concurrently, as `stop_container` would. Returns
+/// the ones that were stopped.
+pub async fn stop_containers(
+    state: &Arc<Mutex<ContainerState>>,
+    ids: Vec<String>,
+    timeout: Option<Duration>,
+) -> Vec<StoppedContainer> {
+    // Containers can take a while to stop, so stop them all concurrently
+    let mut stops = JoinSet::new();
+    for id in ids {
+        let state = state.clone();
+        stops.spawn(async move { stop_container(&state, &id, timeout).await });
+    }
+    let mut stopped = vec![];
+    while let Some(result) = stops.join_next().await {
+        match result {
+            Ok(Some(container)) => stopped.push(container),
+            Ok(None) => (),
+            Err(e) => error!("stop task failed: {}", e),
+        }
+    }
+    stopped
+}
+
+/// Gracefully stops the container with the given id. The container is sent
+/// its stop signal, and gets `timeout` (or its squishfile's stop timeout) to
+/// exit before being SIGKILLed. Its files and slirp4netns instance are cleaned
+/// up by the reaper once it has actually exited, after which it's removed from
+/// the global state. Returns `None` if the container doesn't exist.
+pub async fn stop_container(
+    state: &Arc<Mutex<ContainerState>>,
+    id: &str,
+    timeout: Option<Duration>,
+) -> Option<StoppedContainer> {
+    let final_signal = halt_container(state, id, timeout).await?;
+
+    let mut container_state = state.lock().unwrap();
+    let container = container_state.get_container(id)?.clone();
+    if let Err(e) = container_state.remove_all_containers(vec![id.to_string()]) {
+        error!("Failed to remove container {}: {}", id, e);
+    }
+    Some(StoppedContainer {
+        id: container.id,
+        name: container.name,
+        signal: final_signal.map(|signal| signal.to_string()),
+        exit: container.exit,
+    })
+}
+
+/// Restarts the container with the given id. The container is stopped in the
+/// same way as `stop_container`, after which pid1 and slirp4netns are spawned
+/// again from the container's squishfile. The container keeps its id, name,
+/// labels and port forwards. Returns `None` if the container doesn't exist.
+pub async fn restart_container(
+    state: &Arc<Mutex<ContainerState>>,
+    id: &str,
+) -> SyncResult<Option<RunningContainer>> {
+    let squishfile = match state.lock().unwrap().get_container(id) {
+        Some(container) => container.squishfile.clone(),
+        None => return Ok(None),
+    };
+    let halted = match halt_container(state, id, None).await {
+        Some(_) => state
+            .lock()
+            .unwrap()
+            .get_container(id)
+            .map(|c| (c.slirp_pid, c.status == ContainerStatus::Exited)),
+        None => None,
+    };
+    let slirp_pid = match halted {
+        Some((slirp_pid, true)) => slirp_pid,
+        // Respawning into the same directory and ports while the old pid1 may
+        // still be using them would only make things worse
+        Some((_, false)) => return Err(Box::new(SquishError::ContainerDidntStop(id.to_string()))),
+        None => return Ok(None),
+    };
+    // The old slirp4netns instance has to let go of the host ports before
+    // they can be forwarded again.
+    if !wait_for_process_exit(slirp_pid, KILL_TIMEOUT).await {
+        error!("slirp4netns for {} didn't exit, not respawning it", id);
+        return Err(Box::new(SquishError::ContainerDidntStop(id.to_string())));
+    }
+
+    info!("respawning container {}", id);
+    let mut spawned = super::spawn_container(id, &squishfile).await?;
+    let mut container_state = state.lock().unwrap();
+    let (pid, slirp_pid, pidfd) = (spawned.pid, spawned.slirp_pid, spawned.pidfd.take());
+    if !container_state.replace_container_processes(id, spawned) {
+        // Stopped while we were respawning it
+        send_signal(id, pid, Signal::SIGKILL);
+        send_signal(id, slirp_pid, Signal::SIGTERM);
+        return Ok(None);
+    }
+    watch_container(state.clone(), pid, pidfd);
+    container_state.emit_started(id);
+    Ok(container_state
+        .get_container(id)
+        .map(RunningContainer::from))
+}
+
+/// Sends a container its stop signal, waits for it to exit, and SIGKILLs it if
+/// it takes too long. Returns the signal that finally ended the container, or
+/// `None` inside the `Some` if it had already exited. Returns `None` if the
+/// container doesn't exist.
+async fn halt_container(
+    state: &Arc<Mutex<ContainerState>>,
+    id: &str,
+    timeout: Option<Duration>,
+) -> Option<Option<Signal>> {
+    let (pid, stop_signal, timeout, running) = {
         let mut container_state = state.lock().unwrap();
+        if container_state.get_container(id)?.status == ContainerStatus::Paused {
+            // A frozen workload can't handle its stop signal
+            if let Err(e) = container_state.resume_container(id) {
+                error!("Failed to resume {} before stopping it: {}", id, e);
+            }
+        }
+        let container = container_state.id_map.get_mut(id)?;
+        let running = container.status != ContainerStatus::Exited;
+        if running {
+            container.status = ContainerStatus::Stopping;
+        }
+        (
+            container.pid,
+            container.stop_signal(),
+            timeout.unwrap_or_else(|| container.stop_timeout()),
+            running,
+        )
+    };
 
-        // This is dumb, but it SHOULD be dropped at the end of the scope so it
-        // SHOULD be fine?
-        let clone = container_state.pid_id_map.clone();
+    let mut final_signal = None;
+    if running {
+        info!("stopping {} with {}", id, stop_signal);
+        final_signal = Some(stop_signal);
+        send_signal(id, pid, stop_signal);
+        if tokio::time::timeout(timeout, wait_for_exit(state, id))
+            .await
+            .is_err()
+        {
+            warn!("{} didn't stop within {:?}, killing it", id, timeout);
+            final_signal = Some(Signal::SIGKILL);
+            send_signal(id, pid, Signal::SIGKILL);
+            if tokio::time::timeout(KILL_TIMEOUT, wait_for_exit(state, id))
+                .await
+                .is_err()
+            {
+                error!("{} didn't die after SIGKILL, cleaning up anyway", id);
+            }
+        }
+    }
+    Some(final_signal)
+}
 
-        for (pid, id) in clone.iter() {
-            // debug!("checking {}", pid.as_raw());
-            let path = format!("/proc/{}", pid.as_raw());
-            let path = Path::new(&path);
-            if !path.exists() {
-                match container_state.remove_container(id) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        error!("Failed to remove container files for {}: {}", id, e);
-                    }
+/// Waits for a process to go away, giving up after `timeout`. Returns whether
+/// it went away.
+async fn wait_for_process_exit(pid: Pid, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while procfs::is_alive(pid) {
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    true
+}
+
+fn send_signal(id: &str, pid: Pid, signal: Signal) {
+    if let Err(e) = kill(pid, signal) {
+        error!("Failed to send {} to container {}: {}", signal, id, e);
+    }
+}
+
+/// Whether a user-chosen container name is acceptable: letters, digits, `_`,
+/// `.` and `-`, starting with a letter or digit.
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphanumeric() => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+        }
+        _ => false,
+    }
+}
+
+/// Reaps a container the moment its process exits, recording how it ended.
+/// squishd is a child subreaper, so containers are reparented to it once their
+/// pid1 launcher exits, and stay zombies until they're waited on here. The
+/// pidfd means a recycled pid can never be mistaken for the container; kernels
+/// without pidfds fall back to waiting on the pid whenever SIGCHLD arrives.
+pub fn watch_container(state: Arc<Mutex<ContainerState>>, pid: Pid, pidfd: Option<OwnedFd>) {
+    tokio::spawn(async move {
+        let (code, signal) = match pidfd {
+            Some(pidfd) => match pidfd::wait(pidfd).await {
+                Ok(status) => status,
+                Err(e) => {
+                    error!("Failed to wait on container {}: {}", pid.as_raw(), e);
+                    wait_on_sigchld(pid).await
                 }
-                match cleanup_container(id) {
-                    Ok(_) => info!("cleaned up dead container {}", pid.as_raw()),
-                    Err(e) => error!("error cleaning up dead container {}: {}", pid.as_raw(), e),
+            },
+            None => wait_on_sigchld(pid).await,
+        };
+        let exited_at = libsquish::now().unwrap_or_default();
+        let fallback = ExitStatus {
+            code,
+            signal,
+            exited_at,
+        };
+
+        let mut container_state = state.lock().unwrap();
+        // It may have been removed, or restarted with a new pid, while this
+        // was waiting on it.
+        let id = match container_state.pid_id_map.get(&pid) {
+            Some(id) => id.clone(),
+            None => return,
+        };
+        match container_state.reap_container(&id, fallback) {
+            Ok(_) => info!("cleaned up dead container {}", pid.as_raw()),
+            Err(e) => error!("error cleaning up dead container {}: {}", pid.as_raw(), e),
+        }
+    });
+}
+
+/// Waits for a process to exit without a pidfd, reaping it if it's squishd's
+/// child. Non-children don't raise SIGCHLD, so this checks back every second
+/// regardless.
+async fn wait_on_sigchld(pid: Pid) -> (Option<i32>, Option<i32>) {
+    // Subscribe before the first check, so an exit can't slip in between them
+    let mut sigchld = match unix_signal(SignalKind::child()) {
+        Ok(sigchld) => Some(sigchld),
+        Err(e) => {
+            warn!("couldn't listen for SIGCHLD: {}", e);
+            None
+        }
+    };
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL)) {
+            Ok(WaitStatus::Exited(_, code)) => return (Some(code), None),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return (None, Some(signal as i32)),
+            Ok(_) => (),
+            Err(Errno::ECHILD) => {
+                // Not our child, so all we can do is notice that it's gone.
+                if !Path::new(&format!("/proc/{}", pid.as_raw())).exists() {
+                    return (None, None);
                 }
             }
+            Err(e) => {
+                error!("Failed to wait on container {}: {}", pid.as_raw(), e);
+                return (None, None);
+            }
+        }
+        match &mut sigchld {
+            Some(sigchld) => {
+                let _ = tokio::time::timeout(Duration::from_secs(1), sigchld.recv()).await;
+            }
+            None => sleep(Duration::from_secs(1)).await,
         }
     }
 }
 
-fn cleanup_container(id: &str) -> Result<()> {
-    fs::remove_dir_all(path_to(id))?;
-    fs::remove_file(format!("/tmp/slirp4netns-{}.sock", id))?;
+/// Reads the workload's exit status, as written by pid1 just before it exits.
+fn read_exit_file(id: &str) -> Option<ExitStatus> {
+    let json = fs::read_to_string(format!("{}/exit.json", path_to(id))).ok()?;
+    ExitStatus::from_json(json.as_str()).ok()
+}
+
+fn kill_slirp(container: &Container) {
+    if let Err(e) = kill(container.slirp_pid, signal::SIGTERM) {
+        error!(
+            "Failed to kill slirp4netns for container {}: {}",
+            container.id, e
+        );
+    }
+}
+
+/// Freezes or thaws all of a container's processes.
+fn freeze_container(container: &Container, frozen: bool) -> std::result::Result<(), SquishError> {
+    if let Some(cgroup) = &container.cgroup {
+        return cgroup::set_frozen(cgroup, frozen)
+            .map_err(|e| SquishError::GenericError(Box::new(e)));
+    }
+    let signal = if frozen {
+        Signal::SIGSTOP
+    } else {
+        Signal::SIGCONT
+    };
+    // Without the member list, nothing would be signalled and the container
+    // would wrongly be marked as paused
+    let members = procfs::pid_namespace_members(container.pid)
+        .map_err(|e| SquishError::GenericError(Box::new(e)))?;
+    if !members.contains(&container.pid) {
+        return Err(SquishError::ContainerNotRunning(container.id.clone()));
+    }
+    for pid in members {
+        if let Err(e) = kill(pid, signal) {
+            // The process may have exited in the meantime
+            if e != Errno::ESRCH {
+                return Err(SquishError::GenericError(Box::new(e)));
+            }
+        }
+    }
     Ok(())
 }
 
+fn cleanup_container(container: &Container) -> Result<()> {
+    let result = remove_container_files(container);
+    if result.is_err() {
+        metrics::CLEANUP_FAILURES.inc();
+    }
+    result
+}
+
+fn remove_container_files(container: &Container) -> Result<()> {
+    fs::remove_dir_all(path_to(&container.id))?;
+    if let Some(cgroup) = &container.cgroup {
+        cgroup::remove(cgroup)?;
+    }
+    // slirp4netns may have already removed its socket on its way out
+    match fs::remove_file(super::slirp::socket_path(&container.id)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(Box::new(e)),
+        _ => Ok(()),
+    }
+}
+
 pub fn path_to(id: &str) -> String {
-    format!("container/{}", id)
+    format!("{}/{}", config::get().containers_dir().display(), id)
 }
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+/// Temporary files and directories all start with this, so that they can be
+/// told apart from the real thing and cleaned up if squishd dies mid-download.
+const TEMP_PREFIX: &str = ".tmp-";
+
+lazy_static! {
+    /// One lock per thing that's ever been downloaded, eg. `alpine-3.14-x86_64`.
+    /// There are only ever a handful, so they're never removed.
+    static ref IN_FLIGHT: Mutex<HashMap<String, Arc<AsyncMutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Waits until nothing else is downloading `key`, then holds on to it until
+/// the guard is dropped. Whoever gets it after someone else should check
+/// whether it's cached by now before downloading it again.
+pub async fn lock(key: &str) -> OwnedMutexGuard<()> {
+    let lock = IN_FLIGHT
+        .lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_default()
+        .clone();
+    lock.lock_owned().await
+}
+
+/// A fresh path in `dir` to write `name` to before renaming it into place.
+pub fn temp_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!(
+        "{}{}.{}.{}",
+        TEMP_PREFIX,
+        name,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Writes a file by writing a temporary one next to it and renaming that over
+/// it, so that nothing ever sees it half-written.
+pub fn write_atomically(path: &Path, bytes: &[u8], mode: u32) -> io::Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp = temp_path(dir, &name);
+    let result = (|| {
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(bytes)?;
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Moves a file or directory that may still be in use out of the way, eg. a
+/// rootfs that running containers have bind-mounted. It's left in the same
+/// directory under a temporary name, for `clean_temp_files` to remove at the
+/// next boot.
+pub fn retire(path: &Path) -> io::Result<PathBuf> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let retired = temp_path(dir, &name);
+    fs::rename(path, &retired)?;
+    Ok(retired)
+}
+
+/// Removes the temporary files and directories in `dir` that a squishd which
+/// died mid-download left behind, or that were retired while in use. This must
+/// only be called at boot, before anything is downloaded. Returns how many
+/// were removed.
+pub fn clean_temp_files(dir: &Path) -> usize {
+    let entries = fs::read_dir(dir).into_iter().flatten().flatten();
+    let mut removed = 0;
+    for entry in entries {
+        if !entry.file_name().to_string_lossy().starts_with(TEMP_PREFIX) {
+            continue;
+        }
+        let path = entry.path();
+        let result = match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => fs::remove_dir_all(&path),
+            _ => fs::remove_file(&path),
+        };
+        match result {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("couldn't remove {}: {}", path.display(), e),
+        }
+    }
+    removed
+}
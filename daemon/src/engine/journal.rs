@@ -0,0 +1,212 @@
+use crate::config;
+use crate::engine::containers::path_to;
+use crate::engine::{cgroup, procfs};
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use libsquish::SyncResult;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+
+/// How long to give orphaned processes to die after SIGKILL before removing
+/// their cgroup anyway.
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What squishd started for a container, so that the next squishd can clean up
+/// after it if this one dies without doing so. It lives in the container's
+/// directory.
+#[derive(Serialize, Deserialize)]
+struct Journal {
+    /// Start times only identify a process until the next reboot.
+    boot_id: Option<String>,
+    processes: Vec<JournalledProcess>,
+    cgroup: Option<String>,
+    /// Set when squishd shut down with `--keep-containers`.
+    #[serde(default)]
+    kept: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalledProcess {
+    pid: i32,
+    /// See `procfs::start_time`. A live process with this pid but a different
+    /// start time isn't ours.
+    start_time: u64,
+}
+
+/// What `clean_orphans` found and did.
+#[derive(Default)]
+pub struct CleanupSummary {
+    pub containers: usize,
+    pub processes: usize,
+    pub sockets: usize,
+    /// Containers left running by `--keep-containers` that were left alone.
+    pub kept: usize,
+}
+
+fn path(id: &str) -> PathBuf {
+    PathBuf::from(path_to(id)).join("journal.json")
+}
+
+/// Records the processes started for a container, replacing any earlier
+/// record, eg. from before it was restarted.
+pub fn record(id: &str, pids: &[Pid], cgroup: Option<&str>) -> SyncResult<()> {
+    let processes = pids
+        .iter()
+        .filter_map(|pid| {
+            Some(JournalledProcess {
+                pid: pid.as_raw(),
+                start_time: procfs::start_time(*pid)?,
+            })
+        })
+        .collect();
+    write(
+        id,
+        &Journal {
+            boot_id: procfs::boot_id(),
+            processes,
+            cgroup: cgroup.map(String::from),
+            kept: false,
+        },
+    )
+}
+
+/// Marks a container as deliberately left running, so that it's only cleaned
+/// up once it has exited.
+pub fn mark_kept(id: &str) -> SyncResult<()> {
+    let mut journal = read(id)?;
+    journal.kept = true;
+    write(id, &journal)
+}
+
+fn read(id: &str) -> SyncResult<Journal> {
+    Ok(serde_json::from_str(&fs::read_to_string(path(id))?)?)
+}
+
+fn write(id: &str, journal: &Journal) -> SyncResult<()> {
+    // Never leave a half-written journal behind if squishd dies mid-write
+    let path = path(id);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(journal)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Returns the journalled processes that are still alive, and still the same
+/// processes that were journalled.
+fn live_processes(journal: &Journal) -> Vec<Pid> {
+    if journal.boot_id.is_none() || journal.boot_id != procfs::boot_id() {
+        // Nothing survives a reboot
+        return vec![];
+    }
+    journal
+        .processes
+        .iter()
+        .map(|process| (Pid::from_raw(process.pid), process.start_time))
+        .filter(|(pid, start_time)| {
+            procfs::is_alive(*pid) && procfs::start_time(*pid) == Some(*start_time)
+        })
+        .map(|(pid, _)| pid)
+        .collect()
+}
+
+/// Cleans up after containers left behind by a squishd that died without
+/// stopping them: kills their journalled processes, and removes their cgroups,
+/// directories and slirp4netns sockets. Containers left running on purpose by
+/// `--keep-containers` are left alone until they exit. This must only be
+/// called at boot, before squishd has started any containers of its own.
+pub fn clean_orphans() -> CleanupSummary {
+    let config = config::get();
+    let mut summary = CleanupSummary::default();
+    let mut kept = HashSet::new();
+
+    let entries = fs::read_dir(config.containers_dir())
+        .into_iter()
+        .flatten()
+        .flatten();
+    for entry in entries {
+        let id = entry.file_name().to_string_lossy().into_owned();
+        // A container without a journal never got as far as starting anything
+        let journal = read(&id).ok();
+        let alive = journal.as_ref().map(live_processes).unwrap_or_default();
+        // A kept container is only still running if its own process is, rather
+        // than just slirp4netns
+        let running = journal
+            .as_ref()
+            .and_then(|journal| journal.processes.first())
+            .is_some_and(|process| alive.contains(&Pid::from_raw(process.pid)));
+        if journal.as_ref().is_some_and(|journal| journal.kept) && running {
+            info!("leaving kept container {} running", id);
+            kept.insert(id);
+            summary.kept += 1;
+            continue;
+        }
+
+        for pid in &alive {
+            match kill(*pid, Signal::SIGKILL) {
+                Ok(_) => {
+                    info!("killed orphaned process {} of container {}", pid, id);
+                    summary.processes += 1;
+                }
+                Err(e) => warn!("couldn't kill orphaned process {}: {}", pid, e),
+            }
+        }
+        if let Some(cgroup) = journal
+            .as_ref()
+            .and_then(|journal| journal.cgroup.as_deref())
+        {
+            // Catches anything that escaped the container's pid namespace
+            let _ = cgroup::kill(cgroup);
+            wait_for_exit(&alive);
+            if let Err(e) = cgroup::remove(cgroup) {
+                warn!("couldn't remove orphaned cgroup {}: {}", cgroup, e);
+            }
+        }
+        match fs::remove_dir_all(entry.path()) {
+            Ok(_) => {
+                info!(
+                    "removed orphaned container directory {}",
+                    entry.path().display()
+                );
+                summary.containers += 1;
+            }
+            Err(e) => warn!("couldn't remove {}: {}", entry.path().display(), e),
+        }
+    }
+
+    let sockets = fs::read_dir(&config.runtime_dir)
+        .into_iter()
+        .flatten()
+        .flatten();
+    for entry in sockets {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let id = match name
+            .strip_prefix("slirp4netns-")
+            .and_then(|name| name.strip_suffix(".sock"))
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        if kept.contains(id) {
+            continue;
+        }
+        match fs::remove_file(entry.path()) {
+            Ok(_) => summary.sockets += 1,
+            Err(e) => warn!("couldn't remove {}: {}", entry.path().display(), e),
+        }
+    }
+    summary
+}
+
+/// Waits for SIGKILLed processes to be gone, for at most `KILL_TIMEOUT`.
+fn wait_for_exit(pids: &[Pid]) {
+    let deadline = Instant::now() + KILL_TIMEOUT;
+    while pids.iter().any(|pid| procfs::is_alive(*pid)) && Instant::now() < deadline {
+        sleep(Duration::from_millis(10));
+    }
+}
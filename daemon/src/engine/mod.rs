@@ -1,13 +1,25 @@
 pub mod alpine;
+pub mod cgroup;
+pub mod checksum;
 pub mod containers;
+pub mod download;
+pub mod journal;
+pub mod pidfd;
+pub mod procfs;
 pub mod slirp;
+pub mod stats;
 
+use crate::config;
+use crate::metrics;
+
+use std::collections::HashSet;
 use std::ffi::CStr;
 use std::fs::File;
 use std::io::Write;
 use std::net::TcpListener;
-use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{FromRawFd, OwnedFd};
 use std::process::{Command, Stdio};
+use std::time::Instant;
 
 use libsquish::squishfile::Squishfile;
 use libsquish::SyncResult;
@@ -17,12 +29,25 @@ use nix::unistd::{lseek, Pid, Whence};
 
 pub const USER_AGENT: &str = "squish (https://github.com/queer/squish)";
 
-/// (container pid, slirp pid)
+/// The processes making up a freshly-spawned container.
+pub struct SpawnedContainer {
+    pub pid: Pid,
+    pub slirp_pid: Pid,
+    /// A pidfd for the container's process, for noticing when it exits. This
+    /// is `None` on kernels that don't support pidfds.
+    pub pidfd: Option<OwnedFd>,
+    /// The container's own cgroup, if squishd was delegated one to create it
+    /// under.
+    pub cgroup: Option<String>,
+}
+
 /// Spawns a container, taking in the new container's ID and the squishfile
 /// describing it. This function copies the squishfile to a temporary directory,
-/// spawns the `pid1` binary, starts the slirp4netns process, and then applies
-/// all port forwards. A tuple of pids (container, slirp4netns) is returned.
-pub async fn spawn_container(id: &str, squishfile: Squishfile) -> SyncResult<(Pid, Pid)> {
+/// creates the container's cgroup if possible, spawns the `pid1` binary,
+/// starts the slirp4netns process, and then applies all port forwards.
+pub async fn spawn_container(id: &str, squishfile: &Squishfile) -> SyncResult<SpawnedContainer> {
+    // Only observed on success, see metrics::spawn_phase
+    let started = Instant::now();
     // TODO: Ensure layers are cached
     for port in squishfile.ports() {
         check_port_bind(port.host())?;
@@ -56,28 +81,38 @@ pub async fn spawn_container(id: &str, squishfile: Squishfile) -> SyncResult<(Pi
 
     // Spawn stuff
     debug!("{}: pid1 setup", &id);
-    let base_version = alpine::VERSION.to_string();
-    let base_arch = alpine::ARCH.to_string();
+    let config = config::get();
+    let base_version = &config.alpine.version;
+    let base_arch = squishfile.arch().as_ref().unwrap_or(&config.alpine.arch);
+    alpine::check_arch(base_arch)?;
     // TODO: Allow not having an alpine base image for "FROM scratch"-equiv containers
     let alpine_version = match squishfile.layers().get("alpine") {
         Some(version) => version
             .version()
             .as_ref()
             .expect("No alpine version present!?"),
-        None => &base_version,
+        None => base_version,
     };
-    alpine::download_base_image(alpine_version, &base_arch).await?;
-    let pid1 = Command::new("target/debug/pid1")
-        .args(vec![
-            "--rootfs",
-            alpine::current_rootfs(alpine_version, &base_arch).as_str(),
-            "--id",
-            id,
-            "--path",
-            containers::path_to(id).as_str(),
-            "--squishfile-memfd",
-            format!("{}", memfd).as_str(),
-        ])
+    alpine::download_base_image(alpine_version, base_arch).await?;
+    slirp::download_slirp4netns().await?;
+    let pid1_started = Instant::now();
+    let cgroup = cgroup::create(id);
+    let mut pid1_args = vec![
+        "--rootfs".to_string(),
+        alpine::current_rootfs(alpine_version, base_arch),
+        "--id".to_string(),
+        id.to_string(),
+        "--path".to_string(),
+        containers::path_to(id),
+        "--squishfile-memfd".to_string(),
+        format!("{}", memfd),
+    ];
+    if let Some(cgroup) = &cgroup {
+        pid1_args.push("--cgroup".to_string());
+        pid1_args.push(cgroup.clone());
+    }
+    let pid1 = Command::new(config.pid1())
+        .args(pid1_args)
         .envs(squishfile.env())
         .output()?;
 
@@ -85,26 +120,59 @@ pub async fn spawn_container(id: &str, squishfile: Squishfile) -> SyncResult<(Pi
     debug!("{}: container spawn stderr:\n{}", &id, stderr);
 
     let stdout = String::from_utf8(pid1.stdout).unwrap();
-    let child_pid = stdout.trim().parse::<i32>()?;
+    let child_pid = match stdout.trim().parse::<i32>() {
+        Ok(child_pid) => child_pid,
+        Err(e) => {
+            if let Some(cgroup) = &cgroup {
+                let _ = cgroup::remove(cgroup);
+            }
+            return Err(e.into());
+        }
+    };
+    // Nothing but squishd can reap the container, so its pid can't have been
+    // reused yet, and the pidfd is sure to be for the right process.
+    let pidfd = match pidfd::open(Pid::from_raw(child_pid)) {
+        Ok(pidfd) => Some(pidfd),
+        Err(e) => {
+            debug!("{}: couldn't open a pidfd: {}", &id, e);
+            None
+        }
+    };
+    // Journal it straight away, so that it can be cleaned up after should
+    // squishd die before it does
+    let child = Pid::from_raw(child_pid);
+    if let Err(e) = journal::record(id, &[child], cgroup.as_deref()) {
+        warn!("{}: couldn't journal container: {}", &id, e);
+    }
+    metrics::spawn_phase("pid1", pid1_started);
 
     debug!("{}: slirp4netns setup", &id);
-    let slirp_socket_path = format!("/tmp/slirp4netns-{}.sock", &id);
-    let slirp = tokio::process::Command::new("cache/slirp4netns")
-        .args(vec![
-            "--configure",
-            "--mtu=65520",
-            "--disable-host-loopback",
-            "--api-socket",
-            slirp_socket_path.as_str(),
-            format!("{}", child_pid).as_str(),
-            "tap0",
-        ])
+    let slirp_started = Instant::now();
+    let slirp_socket_path = slirp::socket_path(id);
+    let mut slirp_args = vec![
+        "--configure".to_string(),
+        format!("--mtu={}", config.slirp.mtu),
+    ];
+    if config.slirp.disable_host_loopback {
+        slirp_args.push("--disable-host-loopback".to_string());
+    }
+    slirp_args.extend([
+        "--api-socket".to_string(),
+        slirp_socket_path.clone(),
+        format!("{}", child_pid),
+        "tap0".to_string(),
+    ]);
+    let slirp = tokio::process::Command::new(slirp::binary())
+        .args(slirp_args)
         // TODO: Should we be capturing these logs?
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()?;
 
     let slirp_pid = slirp.id().expect("no slirp4netns pid!?") as i32;
+    if let Err(e) = journal::record(id, &[child, Pid::from_raw(slirp_pid)], cgroup.as_deref()) {
+        warn!("{}: couldn't journal container: {}", &id, e);
+    }
 
     tokio::spawn(async move {
         // debug!("{}: await slirp4netns exit", &id);
@@ -114,7 +182,10 @@ pub async fn spawn_container(id: &str, squishfile: Squishfile) -> SyncResult<(Pi
         // debug!("{}: s4nns exit: {}:\n--------\nstdout:\n{}\n--------\nstderr:\n{}\n--------", &id, output.status, stdout, stderr);
     });
 
+    metrics::spawn_phase("slirp", slirp_started);
+
     debug!("{}: port forward setup", &id);
+    let port_forwards_started = Instant::now();
     for port in squishfile.ports() {
         slirp::add_port_forward(&slirp_socket_path, port.host(), port.container()).await?;
         debug!(
@@ -124,12 +195,27 @@ pub async fn spawn_container(id: &str, squishfile: Squishfile) -> SyncResult<(Pi
             port.container()
         );
     }
+    metrics::spawn_phase("port_forwards", port_forwards_started);
+    metrics::CREATE_DURATION.observe(started.elapsed().as_secs_f64());
+
+    Ok(SpawnedContainer {
+        pid: Pid::from_raw(child_pid),
+        slirp_pid: Pid::from_raw(slirp_pid),
+        pidfd,
+        cgroup,
+    })
+}
 
-    Ok((Pid::from_raw(child_pid), Pid::from_raw(slirp_pid)))
+/// Finds the first free host port at or after `start` that isn't in `taken`,
+/// for forwarding to a replica.
+pub fn allocate_host_port(start: u16, taken: &HashSet<u16>) -> SyncResult<u16> {
+    (start..=u16::MAX)
+        .find(|port| !taken.contains(port) && check_port_bind(port).is_ok())
+        .ok_or_else(|| format!("no free host port at or after {}", start).into())
 }
 
 fn check_port_bind(port: &u16) -> SyncResult<()> {
-    TcpListener::bind(("127.0.0.1".to_string(), *port))
+    TcpListener::bind((config::get().slirp.host_ip, *port))
         .map(|_| ())
         .map_err(|e| e.into())
 }
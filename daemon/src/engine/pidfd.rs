@@ -0,0 +1,63 @@
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+use nix::libc;
+use nix::unistd::{getpid, Pid};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+/// Opens a pidfd for the given process. Unlike the pid itself, a pidfd always
+/// refers to the process it was opened for, even once that process is gone and
+/// its pid has been handed out again. Needs Linux 5.3 or later.
+pub fn open(pid: Pid) -> io::Result<OwnedFd> {
+    // Safety: pidfd_open takes no pointers, and on success returns a new fd
+    // that nothing else owns.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Whether the kernel supports pidfds.
+pub fn supported() -> bool {
+    open(getpid()).is_ok()
+}
+
+/// Waits for the process behind a pidfd to exit, then reaps it. Returns its
+/// exit code or the signal that killed it, or neither if it wasn't squishd's
+/// child to reap.
+pub async fn wait(pidfd: OwnedFd) -> io::Result<(Option<i32>, Option<i32>)> {
+    // A pidfd becomes readable once its process has exited
+    let pidfd = AsyncFd::with_interest(pidfd, Interest::READABLE)?;
+    let _ = pidfd.readable().await?;
+
+    let mut info = MaybeUninit::<libc::siginfo_t>::zeroed();
+    // Safety: info is a valid siginfo_t for waitid to fill in. The process has
+    // already exited, so this doesn't block.
+    let result = unsafe {
+        libc::waitid(
+            libc::P_PIDFD,
+            pidfd.as_raw_fd() as libc::id_t,
+            info.as_mut_ptr(),
+            libc::WEXITED | libc::__WALL,
+        )
+    };
+    if result != 0 {
+        let error = io::Error::last_os_error();
+        return match error.raw_os_error() {
+            // Not our child, so all we can know is that it's gone
+            Some(libc::ECHILD) => Ok((None, None)),
+            _ => Err(error),
+        };
+    }
+    // Safety: waitid succeeded, so it filled info in
+    let info = unsafe { info.assume_init() };
+    let status = unsafe { info.si_status() };
+    Ok(match info.si_code {
+        libc::CLD_EXITED => (Some(status), None),
+        libc::CLD_KILLED | libc::CLD_DUMPED => (None, Some(status)),
+        _ => (None, None),
+    })
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use nix::unistd::Pid;
+
+/// Returns every process that shares a pid namespace with the given pid,
+/// including the pid itself. For a container, this is pid1 and everything the
+/// workload has spawned. Fails if the pid's namespace or `/proc` itself can't
+/// be read, eg. because the pid is already gone.
+pub fn pid_namespace_members(pid: Pid) -> io::Result<Vec<Pid>> {
+    let namespace = fs::read_link(format!("/proc/{}/ns/pid", pid.as_raw()))?;
+    let members = fs::read_dir("/proc")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|candidate| {
+            // Processes can disappear out from under us at any point, in which
+            // case they're no longer members anyway.
+            match fs::read_link(format!("/proc/{}/ns/pid", candidate)) {
+                Ok(candidate_namespace) => candidate_namespace == namespace,
+                Err(_) => false,
+            }
+        })
+        .map(Pid::from_raw)
+        .collect();
+    Ok(members)
+}
+
+/// Resource usage of a single process, as read from procfs.
+pub struct ProcessStats {
+    /// User plus system CPU time, in clock ticks.
+    pub cpu_ticks: u64,
+    pub rss_bytes: u64,
+    pub open_fds: usize,
+}
+
+/// Reads a process's resource usage, returning `None` if it's already gone.
+pub fn process_stats(pid: Pid) -> Option<ProcessStats> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid.as_raw())).ok()?;
+    // See proc(5). The command name is in parentheses and may itself contain
+    // spaces or parentheses, so start after the last closing one. utime and
+    // stime are then the 12th and 13th fields.
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let cpu_ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+
+    let status = fs::read_to_string(format!("/proc/{}/status", pid.as_raw())).ok()?;
+    let rss_bytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|rss| rss.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        // Kernel threads and zombies don't have an RSS
+        .map_or(0, |kb| kb * 1024);
+
+    let open_fds = fs::read_dir(format!("/proc/{}/fd", pid.as_raw()))
+        .map(|fds| fds.count())
+        .unwrap_or(0);
+
+    Some(ProcessStats {
+        cpu_ticks,
+        rss_bytes,
+        open_fds,
+    })
+}
+
+/// Returns when the given process started, in clock ticks since boot. Together
+/// with the pid, this identifies a process for as long as the system is up,
+/// even once the pid itself has been reused.
+pub fn start_time(pid: Pid) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid.as_raw())).ok()?;
+    // starttime is the 22nd field; see process_stats for why this skips ahead
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    fields.get(19)?.parse().ok()
+}
+
+/// Whether the given process exists and hasn't exited. Zombies count as having
+/// exited, since only their parent can make them go away.
+pub fn is_alive(pid: Pid) -> bool {
+    let stat = match fs::read_to_string(format!("/proc/{}/stat", pid.as_raw())) {
+        Ok(stat) => stat,
+        Err(_) => return false,
+    };
+    // The state comes straight after the command name
+    let state = stat
+        .rfind(')')
+        .and_then(|end| stat[end + 1..].split_whitespace().next());
+    !matches!(state, None | Some("Z") | Some("X"))
+}
+
+/// Returns a random id that the kernel generates afresh on every boot.
+pub fn boot_id() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .ok()
+        .map(|id| id.trim().to_string())
+}
+
+/// Returns the total bytes received and sent over every non-loopback interface
+/// in the network namespace of the given process.
+pub fn network_bytes(pid: Pid) -> Option<(u64, u64)> {
+    let dev = fs::read_to_string(format!("/proc/{}/net/dev", pid.as_raw())).ok()?;
+    // The first two lines are headers
+    let totals = dev.lines().skip(2).filter_map(|line| {
+        let (interface, counters) = line.split_once(':')?;
+        if interface.trim() == "lo" {
+            return None;
+        }
+        let counters: Vec<u64> = counters
+            .split_whitespace()
+            .filter_map(|counter| counter.parse().ok())
+            .collect();
+        // Received bytes come first, and sent bytes are the 9th counter
+        Some((*counters.first()?, *counters.get(8)?))
+    });
+    Some(totals.fold((0, 0), |(rx, tx), (r, t)| (rx + r, tx + t)))
+}
+
+/// Whether unprivileged users are allowed to create user namespaces. Besides
+/// `user.max_user_namespaces`, Debian-derived kernels have a switch of their
+/// own, and Ubuntu can restrict them to AppArmor-approved programs.
+pub fn unprivileged_userns() -> bool {
+    let sysctl = |name: &str| {
+        fs::read_to_string(format!("/proc/sys/{}", name))
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+    Path::new("/proc/self/ns/user").exists()
+        && sysctl("user/max_user_namespaces") != Some(0)
+        && sysctl("kernel/unprivileged_userns_clone") != Some(0)
+        && sysctl("kernel/apparmor_restrict_unprivileged_userns") != Some(1)
+}
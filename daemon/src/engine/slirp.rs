@@ -1,4 +1,9 @@
+use crate::config;
+use crate::engine::alpine;
+use crate::engine::checksum::{self, Digest};
+use crate::engine::download;
 use crate::engine::USER_AGENT;
+use crate::metrics;
 use crate::util::SquishError;
 
 use std::fs;
@@ -6,43 +11,214 @@ use std::fs::Permissions;
 use std::io::{Read, Write};
 use std::os::unix::net::UnixStream;
 use std::os::unix::prelude::PermissionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use libsquish::SyncResult;
 use tokio::time::sleep;
 
-const URL: &str = "https://github.com/rootless-containers/slirp4netns/releases/download/v1.1.11/slirp4netns-x86_64";
+const VERSION: &str = "1.1.11";
+
+/// The sha256 digest of each `VERSION` release asset, keyed by asset name like
+/// `asset`, as published in the release's SHA256SUMS. Downloads are always
+/// checked against these, unless `slirp.sha256` overrides them, and assets
+/// without one are never downloaded.
+// TODO: Fill in from https://github.com/rootless-containers/slirp4netns/releases/download/v1.1.11/SHA256SUMS
+const SHA256S: &[(&str, &str)] = &[];
+
+/// The name of the release asset for the given architecture. slirp4netns runs
+/// on the host rather than in containers, so this is always the host's.
+fn asset(arch: &str) -> Result<&'static str, SquishError> {
+    // Release assets are named after `uname -m` rather than Alpine's naming
+    match arch {
+        "x86_64" => Ok("x86_64"),
+        "aarch64" => Ok("aarch64"),
+        "ppc64le" => Ok("ppc64le"),
+        "s390x" => Ok("s390x"),
+        "armv7" => Ok("armv7l"),
+        _ => Err(SquishError::NoArtefactForArch(
+            "slirp4netns download".to_string(),
+            arch.to_string(),
+        )),
+    }
+}
+
+/// Where to download the given release asset from.
+fn url(asset: &str) -> String {
+    format!(
+        "https://github.com/rootless-containers/slirp4netns/releases/download/v{}/slirp4netns-{}",
+        VERSION, asset
+    )
+}
+
+/// The digest that the given release asset must have: `slirp.sha256` if it's
+/// set, or else the one pinned for it.
+fn expected_digest(asset: &str) -> SyncResult<Digest> {
+    if let Some(sha256) = &config::get().slirp.sha256 {
+        return Ok(Digest::Sha256(sha256.clone()));
+    }
+    match SHA256S.iter().find(|(name, _)| *name == asset) {
+        Some((_, sha256)) => Ok(Digest::Sha256(sha256.to_string())),
+        None => Err(format!(
+            "no sha256 is pinned for slirp4netns-{} {}, so it can't be downloaded safely: set slirp.sha256, or import a binary with `squish cache import`",
+            asset, VERSION
+        )
+        .into()),
+    }
+}
+
+/// The slirp4netns binary to run. This is either the one set in the config, or
+/// the one downloaded into the cache directory.
+pub fn binary() -> PathBuf {
+    let config = config::get();
+    match &config.slirp.binary {
+        Some(binary) => binary.clone(),
+        None => cached_binary(),
+    }
+}
+
+/// Where a downloaded or imported slirp4netns binary is cached.
+pub fn cached_binary() -> PathBuf {
+    config::get()
+        .cache_dir
+        .join(format!("slirp4netns-{}", alpine::host_arch()))
+}
+
+/// The path to the API socket of the given container's slirp4netns instance.
+pub fn socket_path(id: &str) -> String {
+    format!(
+        "{}/slirp4netns-{}.sock",
+        config::get().runtime_dir.display(),
+        id
+    )
+}
+
+/// Asks the slirp4netns binary for its version. Returns `None` if it couldn't
+/// be run.
+pub async fn version() -> Option<String> {
+    let line = version_line(&binary()).await?;
+    Some(
+        line.strip_prefix(VERSION_PREFIX)
+            .unwrap_or(&line)
+            .to_string(),
+    )
+}
+
+/// How slirp4netns starts the first line of its `--version` output, eg.
+/// "slirp4netns version 1.1.11", followed by commit and libslirp info.
+const VERSION_PREFIX: &str = "slirp4netns version ";
+
+async fn version_line(binary: &Path) -> Option<String> {
+    let output = tokio::process::Command::new(binary)
+        .arg("--version")
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    Some(stdout.lines().next()?.trim().to_string())
+}
 
 /// Downloads the current slirp4netns binary. This caches in the same directory
-/// as the Alpine rootfs images.
-pub async fn download_slirp4netns() -> SyncResult<&'static str> {
+/// as the Alpine rootfs images. Nothing is downloaded if the config points at
+/// a binary of its own. Concurrent calls wait on a single download.
+pub async fn download_slirp4netns() -> SyncResult<PathBuf> {
     // TODO: Version this
-    let output_path = "cache/slirp4netns";
-    if Path::new(output_path).exists() {
+    let output_path = binary();
+    if let Some(configured) = &config::get().slirp.binary {
+        if !configured.exists() {
+            return Err(format!(
+                "configured slirp4netns binary {} doesn't exist",
+                configured.display()
+            )
+            .into());
+        }
+        return Ok(output_path);
+    }
+    if Path::new(&output_path).exists() {
         info!("slirp4netns binary already exists, not downloading again");
+        metrics::cache_lookup("slirp4netns", true);
+        return Ok(output_path);
+    }
+    let _guard = download::lock(&format!("slirp4netns-{}", alpine::host_arch())).await;
+    if Path::new(&output_path).exists() {
+        // Someone else just downloaded it
+        metrics::cache_lookup("slirp4netns", true);
         return Ok(output_path);
     }
-    info!("downloading slirp4netns binary from {}", URL);
+    metrics::cache_lookup("slirp4netns", false);
+    if config::get().offline {
+        return Err(Box::new(SquishError::NotCached("slirp4netns".to_string())));
+    }
+    let asset = asset(alpine::host_arch())?;
+    // Find out what it has to hash to before downloading anything
+    let digest = expected_digest(asset)?;
+    let url = url(asset);
+    info!("downloading slirp4netns binary from {}", url);
     // TODO: Refactor this to reuse code from alpine / layers where possible
     let slirp_bytes = reqwest::Client::builder()
         .user_agent(USER_AGENT)
         .build()?
-        .get(URL)
+        .get(url)
         .send()
         .await?
+        .error_for_status()?
         .bytes()
         .await?;
-    let mut output_file = fs::OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&output_path)?;
-    output_file.write_all(&slirp_bytes)?;
-    fs::set_permissions(output_path, Permissions::from_mode(0o755))?;
-    // eprintln!("{:o}", output_file.metadata()?.permissions().mode());
+    metrics::DOWNLOAD_BYTES
+        .with_label_values(&["slirp4netns"])
+        .inc_by(slirp_bytes.len() as u64);
+    checksum::verify_download("slirp4netns", &slirp_bytes, &digest)?;
+    // The digest goes first, so that the binary is never cached without it
+    checksum::record(&output_path, &digest)?;
+    download::write_atomically(&output_path, &slirp_bytes, 0o755)?;
     Ok(output_path)
 }
 
+/// Imports a slirp4netns binary from elsewhere, eg. one copied onto a machine
+/// without network access, into the cache as if it had been downloaded. The
+/// binary has to run, and say that it's slirp4netns. Returns its version.
+pub async fn import_binary(bytes: &[u8]) -> SyncResult<String> {
+    let output_path = cached_binary();
+    let staging = download::temp_path(&config::get().cache_dir, "slirp4netns");
+    fs::write(&staging, bytes)?;
+    fs::set_permissions(&staging, Permissions::from_mode(0o755))?;
+    let line = version_line(&staging).await;
+    let version = match line
+        .as_deref()
+        .and_then(|line| line.strip_prefix(VERSION_PREFIX))
+    {
+        Some(version) => version.to_string(),
+        None => {
+            let _ = fs::remove_file(&staging);
+            return Err(Box::new(SquishError::InvalidImport(
+                "not a slirp4netns binary that runs here".to_string(),
+            )));
+        }
+    };
+    let _guard = download::lock(&format!("slirp4netns-{}", alpine::host_arch())).await;
+    let result = checksum::record(&output_path, &Digest::sha256_of(bytes))
+        .and_then(|_| fs::rename(&staging, &output_path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&staging);
+        return Err(e.into());
+    }
+    if let Some(configured) = &config::get().slirp.binary {
+        warn!(
+            "imported slirp4netns, but {} is configured to be used instead",
+            configured.display()
+        );
+    }
+    info!(
+        "imported slirp4netns {} into {}",
+        version,
+        output_path.display()
+    );
+    Ok(version)
+}
+
 /// Adds a port-forward to the given slirp4netns instance via its socket.
 pub async fn add_port_forward(socket: &str, host: &u16, container: &u16) -> SyncResult<String> {
     slirp_exec(
@@ -53,13 +229,15 @@ pub async fn add_port_forward(socket: &str, host: &u16, container: &u16) -> Sync
             "execute": "add_hostfwd",
             "arguments": {{
                 "proto": "tcp",
-                "host_ip": "127.0.0.1",
+                "host_ip": "{}",
                 "host_port": {},
                 "guest_port": {}
             }}
         }}
     "#,
-            host, container
+            config::get().slirp.host_ip,
+            host,
+            container
         )
         .as_str(),
     )
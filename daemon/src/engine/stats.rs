@@ -0,0 +1,49 @@
+use crate::engine::{cgroup, procfs};
+
+use libsquish::ContainerStats;
+use nix::unistd::{sysconf, Pid, SysconfVar};
+
+/// Measures the resource usage of the container whose pid1 has the given pid.
+/// Every process in the container's pid namespace is counted. CPU time and
+/// memory come from the container's cgroup instead if it has one, as that
+/// also accounts for processes that have already exited.
+pub fn collect(id: &str, name: &str, pid: Pid, cgroup: Option<&str>) -> ContainerStats {
+    let members = procfs::pid_namespace_members(pid).unwrap_or_default();
+    let processes: Vec<procfs::ProcessStats> = members
+        .iter()
+        .filter_map(|member| procfs::process_stats(*member))
+        .collect();
+
+    let cpu_time_ms = cgroup
+        .and_then(cgroup::cpu_usage_usec)
+        .map(|usec| usec / 1000)
+        .unwrap_or_else(|| {
+            let ticks: u64 = processes.iter().map(|p| p.cpu_ticks).sum();
+            ticks * 1000 / clock_ticks_per_second()
+        });
+    let memory_bytes = cgroup
+        .and_then(cgroup::memory_current)
+        .unwrap_or_else(|| processes.iter().map(|p| p.rss_bytes).sum());
+    // The whole container shares pid1's network namespace
+    let (rx_bytes, tx_bytes) = procfs::network_bytes(pid).unwrap_or_default();
+
+    ContainerStats {
+        id: id.to_string(),
+        name: name.to_string(),
+        timestamp: libsquish::now().unwrap_or_default(),
+        cpu_time_ms,
+        memory_bytes,
+        processes: processes.len(),
+        open_fds: processes.iter().map(|p| p.open_fds).sum(),
+        rx_bytes,
+        tx_bytes,
+    }
+}
+
+fn clock_ticks_per_second() -> u64 {
+    match sysconf(SysconfVar::CLK_TCK) {
+        Ok(Some(ticks)) if ticks > 0 => ticks as u64,
+        // Linux has used 100 everywhere for a very long time
+        _ => 100,
+    }
+}
@@ -0,0 +1,68 @@
+use crate::auth::Caller;
+use crate::engine::{alpine, checksum, slirp};
+use crate::util::SquishError;
+
+use libsquish::ImportedFile;
+use serde::Deserialize;
+use warp::hyper::body::Bytes;
+use warp::Rejection;
+
+/// The most that may be imported in one go. Minirootfs tarballs and
+/// slirp4netns binaries are only a few MB each.
+pub const IMPORT_LIMIT: u64 = 64 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct ImportOptions {
+    /// The name of the file being imported, which Alpine tarballs can get
+    /// their architecture from.
+    file: Option<String>,
+    version: Option<String>,
+    arch: Option<String>,
+}
+
+/// Imports an Alpine minirootfs tarball or a slirp4netns binary into the
+/// cache, telling which it is from its contents. The cache is shared by
+/// everyone, so only admins may import into it.
+pub async fn import(
+    options: ImportOptions,
+    caller: Caller,
+    body: Bytes,
+) -> Result<impl warp::Reply, Rejection> {
+    if !caller.admin {
+        return Err(SquishError::Forbidden.into());
+    }
+    let imported = if body.starts_with(&[0x1f, 0x8b]) {
+        let rootfs = alpine::import_rootfs(
+            body,
+            options.file.as_deref(),
+            options.version.as_deref(),
+            options.arch.as_deref(),
+        )
+        .await
+        .map_err(SquishError::from_boxed)?;
+        ImportedFile::Alpine(rootfs)
+    } else if body.starts_with(b"\x7fELF") {
+        let version = slirp::import_binary(&body)
+            .await
+            .map_err(SquishError::from_boxed)?;
+        ImportedFile::Slirp4netns { version }
+    } else {
+        return Err(SquishError::InvalidImport(
+            "expected an alpine minirootfs tarball or a slirp4netns binary".to_string(),
+        )
+        .into());
+    };
+    Ok(warp::reply::json(&imported))
+}
+
+/// Re-checks everything in the cache against the digests recorded when it was
+/// cached, quarantining anything that doesn't match.
+pub async fn verify(caller: Caller) -> Result<impl warp::Reply, Rejection> {
+    if !caller.admin {
+        return Err(SquishError::Forbidden.into());
+    }
+    let verified = tokio::task::spawn_blocking(checksum::verify_cache)
+        .await
+        .map_err(|e| SquishError::GenericError(e.into()))?;
+    Ok(warp::reply::json(&verified))
+}
@@ -1,52 +1,328 @@
+use crate::auth;
+use crate::auth::Caller;
 use crate::engine;
-use crate::engine::containers::ContainerState;
+use crate::engine::containers;
+use crate::engine::containers::{ContainerFilter, ContainerState};
+use crate::util;
 use crate::util::SquishError;
 
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
+use libsquish::event::{ContainerEvent, EventKind};
 use libsquish::squishfile::Squishfile;
+use libsquish::{
+    ContainerStatus, CreatedContainer, RunningContainer, SignalledContainer, SyncResult,
+};
+use nix::sys::signal::Signal;
+use serde::Deserialize;
 use warp::Rejection;
 
+/// Labels every replica created by the same request with the id of the first.
+const REPLICA_GROUP_LABEL: &str = "squish.replica-group";
+/// Labels each replica with its index in its group, starting from 0.
+const REPLICA_LABEL: &str = "squish.replica";
+/// The most containers a single request may create, so that one request can't
+/// use up every port and process on the host.
+pub const MAX_REPLICAS: usize = 64;
+
+#[derive(Deserialize)]
+pub struct CreateOptions {
+    /// How many containers to create from the squishfile, up to
+    /// `MAX_REPLICAS`. Defaults to 1.
+    replicas: Option<usize>,
+    /// The container's name. Replicas get this suffixed with their index.
+    /// Generated if not given.
+    name: Option<String>,
+}
+
 pub async fn create_container(
+    options: CreateOptions,
+    caller: Caller,
     state: Arc<Mutex<ContainerState>>,
-    squishfile: Squishfile,
+    mut squishfile: Squishfile,
 ) -> Result<impl warp::Reply, Rejection> {
-    let (id, name) = ContainerState::generate_id();
+    // Reject a bad stop signal now, rather than when it's needed
+    containers::stop_signal(&squishfile)?;
+    auth::check_layer_paths(&caller, &mut squishfile)?;
+    let replicas = options.replicas.unwrap_or(1);
+    if replicas == 0 || replicas > MAX_REPLICAS {
+        return Err(warp::reject::custom(SquishError::InvalidReplicas(replicas)));
+    }
+
+    let ids = state
+        .lock()
+        .unwrap()
+        .reserve_names(options.name.as_deref(), replicas)?;
+    let names: Vec<String> = ids.iter().map(|(_, name)| name.clone()).collect();
+    let release = || {
+        state
+            .lock()
+            .unwrap()
+            .release_names(names.iter().map(String::as_str))
+    };
+    let squishfiles = match replica_squishfiles(&squishfile, ids) {
+        Ok(squishfiles) => squishfiles,
+        Err(e) => {
+            release();
+            return Err(SquishError::GenericError(e).into());
+        }
+    };
+    let mut created = vec![];
+    for (id, name, squishfile) in squishfiles {
+        match spawn_replica(&caller, &state, &id, name, squishfile).await {
+            Ok(container) => created.push(container),
+            Err(e) => {
+                // Don't leave a partial set of replicas behind
+                let ids = created
+                    .into_iter()
+                    .map(|container: CreatedContainer| container.id)
+                    .collect();
+                if let Err(e) = state.lock().unwrap().remove_all_containers(ids) {
+                    error!("couldn't clean up replicas: {}", e);
+                }
+                release();
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(warp::reply::json(&created))
+}
+
+/// Gives each replica its own copy of the squishfile, alongside its id and
+/// name. The first replica gets the host ports the squishfile asks for, and the
+/// rest get the next free ports after them. When there's more than one
+/// replica, they're all labelled with the id of the first.
+fn replica_squishfiles(
+    squishfile: &Squishfile,
+    ids: Vec<(String, String)>,
+) -> SyncResult<Vec<(String, String, Squishfile)>> {
+    let replicas = ids.len();
+    let group = ids[0].0.clone();
+    let mut taken: HashSet<u16> = squishfile.ports().iter().map(|port| *port.host()).collect();
+    let mut squishfiles = vec![];
+    for (replica, (id, name)) in ids.into_iter().enumerate() {
+        let mut squishfile = squishfile.clone();
+        if replicas > 1 {
+            squishfile.set_label(REPLICA_GROUP_LABEL.to_string(), group.clone());
+            squishfile.set_label(REPLICA_LABEL.to_string(), replica.to_string());
+        }
+        if replica > 0 {
+            for port in squishfile.ports_mut() {
+                let host = engine::allocate_host_port(*port.host(), &taken)?;
+                taken.insert(host);
+                port.set_host(host);
+            }
+        }
+        squishfiles.push((id, name, squishfile));
+    }
+    Ok(squishfiles)
+}
+
+async fn spawn_replica(
+    caller: &Caller,
+    state: &Arc<Mutex<ContainerState>>,
+    id: &str,
+    name: String,
+    squishfile: Squishfile,
+) -> Result<CreatedContainer, SquishError> {
     info!("spawning container {} ({})", name, id);
-    let (container_pid, slirp_pid) = engine::spawn_container(&id, squishfile)
+    state.lock().unwrap().emit(ContainerEvent::new(
+        EventKind::Create,
+        id,
+        name.as_str(),
+        None,
+        caller.uid,
+    ));
+    let mut spawned = engine::spawn_container(id, &squishfile)
         .await
-        .map_err(|e| SquishError::GenericError(e))?;
+        .map_err(SquishError::from_boxed)?;
     info!(
         "spawned container {} in pid {} (slirp={})",
         name,
-        container_pid.as_raw(),
-        slirp_pid.as_raw(),
+        spawned.pid.as_raw(),
+        spawned.slirp_pid.as_raw(),
     );
+    let (pid, pidfd) = (spawned.pid, spawned.pidfd.take());
+    let created = CreatedContainer {
+        id: id.to_string(),
+        name: name.clone(),
+        ports: squishfile.ports().clone(),
+    };
 
     // Minimise use so as to avoid lock contention
     let mut container_state = state.lock().unwrap();
-    // TODO: Real struct for responses someday
     container_state
-        .add_container(container_pid, slirp_pid, &id, name)
+        .add_container(spawned, id, name, squishfile, caller.uid)
         .unwrap();
-    Ok(warp::reply::json(&vec![&id]))
+    containers::watch_container(state.clone(), pid, pidfd);
+    container_state.emit_started(id);
+    Ok(created)
 }
 
 pub async fn list_containers(
+    caller: Caller,
     state: Arc<Mutex<ContainerState>>,
 ) -> Result<impl warp::Reply, Rejection> {
     info!("listing containers");
     let container_state = state.lock().unwrap();
-    let running_containers = container_state.running_containers();
+    let running_containers = container_state.running_containers(&caller);
     Ok(warp::reply::json(&running_containers))
 }
 
+#[derive(Deserialize)]
+pub struct StopOptions {
+    /// Seconds to wait for the container to exit before SIGKILLing it.
+    timeout: Option<u64>,
+}
+
 pub async fn stop_container(
     id: String,
+    options: StopOptions,
+    caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    let id = state.lock().unwrap().resolve(&caller, &id)?.id.clone();
+    let timeout = options.timeout.map(Duration::from_secs);
+    Ok(warp::reply::json(
+        &containers::stop_containers(&state, vec![id], timeout).await,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct BulkStopOptions {
+    /// Seconds to wait for each container to exit before SIGKILLing it.
+    timeout: Option<u64>,
+    /// Stop every container the caller can see.
+    #[serde(default)]
+    all: bool,
+    /// Stop every container matching a `ContainerFilter`.
+    filter: Option<String>,
+}
+
+/// Stops several containers at once. Either `all` or a filter has to be given,
+/// so that nothing is ever stopped in bulk by accident.
+pub async fn stop_containers(
+    options: BulkStopOptions,
+    caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    let filter = match (options.all, &options.filter) {
+        (_, Some(filter)) => Some(filter.parse::<ContainerFilter>()?),
+        (true, None) => None,
+        (false, None) => return Err(SquishError::NothingSelected.into()),
+    };
+    let ids = state.lock().unwrap().select(&caller, filter.as_ref());
+    let timeout = options.timeout.map(Duration::from_secs);
+    Ok(warp::reply::json(
+        &containers::stop_containers(&state, ids, timeout).await,
+    ))
+}
+
+pub async fn wait_container(
+    id: String,
+    caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    let id = state.lock().unwrap().resolve(&caller, &id)?.id.clone();
+    info!("waiting on container {}", id);
+    match containers::wait_for_exit(&state, &id).await {
+        Some(exit) => Ok(warp::reply::json(&exit)),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct KillOptions {
+    /// The signal to send, by name or number. Defaults to SIGKILL.
+    signal: Option<String>,
+}
+
+pub async fn kill_container(
+    id: String,
+    options: KillOptions,
+    caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    let signal = match options.signal {
+        Some(signal) => util::parse_workload_signal(&signal)?,
+        None => Signal::SIGKILL,
+    };
+    let container_state = state.lock().unwrap();
+    let container = container_state.resolve(&caller, &id)?;
+    container_state.signal_container(&container.id, signal)?;
+    Ok(warp::reply::json(&SignalledContainer {
+        id: container.id.clone(),
+        name: container.name.clone(),
+        signal: signal.to_string(),
+    }))
+}
+
+pub async fn pause_container(
+    id: String,
+    caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    let mut container_state = state.lock().unwrap();
+    let id = container_state.resolve(&caller, &id)?.id.clone();
+    container_state.pause_container(&id)?;
+    let container = container_state.get_container(&id).unwrap();
+    Ok(warp::reply::json(&RunningContainer::from(container)))
+}
+
+pub async fn unpause_container(
+    id: String,
+    caller: Caller,
     state: Arc<Mutex<ContainerState>>,
 ) -> Result<impl warp::Reply, Rejection> {
     let mut container_state = state.lock().unwrap();
-    let ids = container_state.fuzzy_remove_container(&id).unwrap();
-    Ok(warp::reply::json(&ids))
+    let id = container_state.resolve(&caller, &id)?.id.clone();
+    container_state.resume_container(&id)?;
+    let container = container_state.get_container(&id).unwrap();
+    Ok(warp::reply::json(&RunningContainer::from(container)))
+}
+
+pub async fn restart_container(
+    id: String,
+    caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    let id = state.lock().unwrap().resolve(&caller, &id)?.id.clone();
+    info!("restarting container {}", id);
+    match containers::restart_container(&state, &id)
+        .await
+        .map_err(SquishError::from_boxed)?
+    {
+        Some(container) => Ok(warp::reply::json(&container)),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+pub async fn container_stats(
+    id: String,
+    caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    let (id, name, pid, cgroup) = {
+        let container_state = state.lock().unwrap();
+        let container = container_state.resolve(&caller, &id)?;
+        if container.status == ContainerStatus::Exited {
+            return Err(SquishError::ContainerNotRunning(container.id.clone()).into());
+        }
+        (
+            container.id.clone(),
+            container.name.clone(),
+            container.pid,
+            container.cgroup.clone(),
+        )
+    };
+    // Walking /proc is all blocking IO
+    let stats = tokio::task::spawn_blocking(move || {
+        engine::stats::collect(&id, &name, pid, cgroup.as_deref())
+    })
+    .await
+    .map_err(|e| SquishError::GenericError(e.into()))?;
+    Ok(warp::reply::json(&stats))
 }
@@ -0,0 +1,42 @@
+use crate::auth::Caller;
+use crate::engine::containers::ContainerState;
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use warp::hyper::Body;
+use warp::Rejection;
+
+/// Streams container lifecycle events as newline-delimited JSON. The stream
+/// stays open until the client disconnects. A client that falls too far behind
+/// silently misses the events it lagged on. Callers only see the events of
+/// containers that they can access.
+pub async fn stream_events(
+    caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    info!("streaming events");
+    let receiver = state.lock().unwrap().subscribe();
+    let stream = BroadcastStream::new(receiver).filter_map(move |event| match event {
+        Ok(event) if !caller.can_access(event.owner) => None,
+        Ok(event) => match serde_json::to_string(&event) {
+            Ok(json) => Some(Ok::<String, Infallible>(json + "\n")),
+            Err(e) => {
+                error!("Failed to serialise event: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            warn!("events subscriber lagged: {}", e);
+            None
+        }
+    });
+    Ok(warp::reply::with_header(
+        warp::reply::Response::new(Body::wrap_stream(stream)),
+        "content-type",
+        "application/x-ndjson",
+    ))
+}
@@ -1,7 +1,109 @@
+use crate::auth::Caller;
+use crate::config;
+use crate::engine::containers::ContainerState;
+use crate::engine::{alpine, cgroup, pidfd, procfs, slirp};
+use crate::metrics;
+use crate::util::SquishError;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use libsquish::{ContainerStatus, DaemonStatus, KernelFeatures};
+use warp::http::StatusCode;
 use warp::Rejection;
 
+pub mod cache;
 pub mod container;
+pub mod events;
+
+lazy_static! {
+    /// When squishd started, for working out its uptime.
+    pub static ref STARTED_AT: Instant = Instant::now();
+}
+
+/// Reports what squishd is, where it keeps things, and what the kernel it's
+/// running on supports.
+pub async fn status(
+    caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    let config = config::get();
+    let containers = state.lock().unwrap().visible_to(caller).count();
+    let features = tokio::task::spawn_blocking(|| KernelFeatures {
+        unprivileged_userns: procfs::unprivileged_userns(),
+        cgroup_delegation: cgroup::delegated_root().is_some(),
+        pidfd: pidfd::supported(),
+    })
+    .await
+    .map_err(|e| SquishError::GenericError(e.into()))?;
+    Ok(warp::reply::json(&DaemonStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        arch: alpine::host_arch().to_string(),
+        uptime_secs: STARTED_AT.elapsed().as_secs(),
+        socket: config.socket().display().to_string(),
+        state_dir: config.state_dir.display().to_string(),
+        cache_dir: config.cache_dir.display().to_string(),
+        alpine: alpine::cached_rootfses(),
+        slirp4netns: slirp::version().await,
+        features,
+        containers,
+    }))
+}
+
+/// Turns squishd's own errors into responses with a fitting status code and
+/// the error as the body. Anything else is left for warp to deal with.
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl warp::Reply, Rejection> {
+    let error = match rejection.find::<SquishError>() {
+        Some(error) => error,
+        None => return Err(rejection),
+    };
+    let status = match error {
+        SquishError::Forbidden | SquishError::ReadOnly | SquishError::ForbiddenPath(_) => {
+            StatusCode::FORBIDDEN
+        }
+        SquishError::NoSuchContainer(_) => StatusCode::NOT_FOUND,
+        SquishError::InvalidSignal(_)
+        | SquishError::InvalidFilter(_)
+        | SquishError::InvalidReplicas(_)
+        | SquishError::InvalidName(_)
+        | SquishError::InvalidImport(_)
+        | SquishError::InvalidAlpineVersion(_)
+        | SquishError::NoArtefactForArch(_, _)
+        | SquishError::CrossArch(_, _)
+        | SquishError::NothingSelected => StatusCode::BAD_REQUEST,
+        SquishError::AmbiguousReference(_, _)
+        | SquishError::NameInUse(_)
+        | SquishError::ContainerNotRunning(_)
+        | SquishError::ContainerNotPaused(_) => StatusCode::CONFLICT,
+        SquishError::NotCached(_) => StatusCode::SERVICE_UNAVAILABLE,
+        SquishError::ChecksumMismatch(_, _, _) => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    Ok(warp::reply::with_status(error.to_string(), status))
+}
 
-pub async fn status() -> Result<impl warp::Reply, Rejection> {
-    Ok(warp::reply::reply())
+/// Serves metrics in the Prometheus text format.
+pub async fn metrics(
+    _caller: Caller,
+    state: Arc<Mutex<ContainerState>>,
+) -> Result<impl warp::Reply, Rejection> {
+    {
+        let container_state = state.lock().unwrap();
+        for status in [
+            ContainerStatus::Running,
+            ContainerStatus::Paused,
+            ContainerStatus::Stopping,
+            ContainerStatus::Exited,
+        ] {
+            metrics::CONTAINERS
+                .with_label_values(&[&status.to_string()])
+                .set(container_state.count_by_status(status) as i64);
+        }
+    }
+    Ok(warp::reply::with_header(
+        metrics::render(),
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
 }
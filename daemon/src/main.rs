@@ -4,84 +4,350 @@ extern crate flate2;
 extern crate haikunator;
 extern crate hex;
 extern crate hmac_sha256;
+extern crate hmac_sha512;
+#[macro_use]
+extern crate lazy_static;
 extern crate libsquish;
 #[macro_use]
 extern crate log;
 extern crate nix;
 extern crate pretty_env_logger;
+extern crate rand;
 extern crate reqwest;
 extern crate tar;
 extern crate tokio;
 extern crate warp;
 extern crate yaml_rust;
 
+use crate::config::Config;
 use crate::engine::containers::ContainerState;
 
+use std::convert::Infallible;
 use std::fs;
+use std::io::{BufReader, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 
+use clap::{Arg, Command};
 use libsquish::SyncResult;
+use nix::unistd::getuid;
 use warp::hyper::body::Bytes;
 use warp::Filter;
 
+mod auth;
+mod config;
 mod engine;
 mod handlers;
+mod metrics;
 mod util;
 
 #[cfg(unix)]
 #[tokio::main]
 
 async fn main() -> SyncResult<()> {
-    use tokio::net::UnixListener;
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio::signal::unix::{signal, SignalKind};
     use tokio_stream::wrappers::UnixListenerStream;
+    use warp::hyper::server::accept;
+    use warp::hyper::service::{make_service_fn, service_fn, Service};
+    use warp::hyper::{Body, Request, Server};
 
     pretty_env_logger::init();
+    let matches = Command::new("squishd")
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .short('c')
+                .takes_value(true)
+                .help("Path to squishd.toml [default: $XDG_CONFIG_HOME/squish/squishd.toml]"),
+        )
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .short('s')
+                .takes_value(true)
+                .help("Unix socket to listen on [default: <runtime dir>/squishd.sock]"),
+        )
+        .arg(
+            Arg::new("state-dir")
+                .long("state-dir")
+                .takes_value(true)
+                .help("Directory to keep containers in [default: $XDG_STATE_HOME/squish]"),
+        )
+        .arg(
+            Arg::new("cache-dir")
+                .long("cache-dir")
+                .takes_value(true)
+                .help("Directory to cache downloads in [default: $XDG_CACHE_HOME/squish]"),
+        )
+        .arg(
+            Arg::new("runtime-dir")
+                .long("runtime-dir")
+                .takes_value(true)
+                .help("Directory to put sockets in [default: $XDG_RUNTIME_DIR/squish]"),
+        )
+        .arg(
+            Arg::new("pid1")
+                .long("pid1")
+                .takes_value(true)
+                .help("pid1 binary to launch containers with [default: next to squishd]"),
+        )
+        .arg(
+            Arg::new("keep-containers")
+                .long("keep-containers")
+                .help("Leave containers running when squishd shuts down"),
+        )
+        .arg(
+            Arg::new("no-prefetch")
+                .long("no-prefetch")
+                .help("Download the Alpine rootfs and slirp4netns when first needed, not at boot"),
+        )
+        .arg(
+            Arg::new("offline")
+                .long("offline")
+                .help("Never download anything, failing instead of downloading what isn't cached"),
+        )
+        .arg(
+            Arg::new("alpine-version")
+                .long("alpine-version")
+                .takes_value(true)
+                .help("Default Alpine version for containers"),
+        )
+        .arg(
+            Arg::new("alpine-arch")
+                .long("alpine-arch")
+                .takes_value(true)
+                .help("Architecture to run containers as [default: the host's]"),
+        )
+        .arg(
+            Arg::new("alpine-mirror")
+                .long("alpine-mirror")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Alpine mirror to download from, in order of preference; repeatable"),
+        )
+        .get_matches();
+
     info!("squishd booting...");
+    lazy_static::initialize(&handlers::STARTED_AT);
+    let mut config = Config::load(matches.value_of("config").map(Path::new))?;
+    if let Some(socket) = matches.value_of("socket") {
+        config.socket = Some(socket.into());
+    }
+    if let Some(state_dir) = matches.value_of("state-dir") {
+        config.state_dir = state_dir.into();
+    }
+    if let Some(cache_dir) = matches.value_of("cache-dir") {
+        config.cache_dir = cache_dir.into();
+    }
+    if let Some(runtime_dir) = matches.value_of("runtime-dir") {
+        config.runtime_dir = runtime_dir.into();
+    }
+    if let Some(pid1) = matches.value_of("pid1") {
+        config.pid1 = Some(pid1.into());
+    }
+    if matches.is_present("keep-containers") {
+        config.keep_containers = true;
+    }
+    if matches.is_present("no-prefetch") {
+        config.prefetch = false;
+    }
+    if matches.is_present("offline") {
+        config.offline = true;
+    }
+    if let Some(version) = matches.value_of("alpine-version") {
+        config.alpine.version = version.to_string();
+    }
+    if let Some(arch) = matches.value_of("alpine-arch") {
+        config.alpine.arch = arch.to_string();
+    }
+    if let Some(mirrors) = matches.values_of("alpine-mirror") {
+        config.alpine.mirrors = mirrors.map(String::from).collect();
+    }
+    debug!("config: {:?}", config);
+    config::init(config);
+    let config = config::get();
+    info!("running on {}", engine::alpine::host_arch());
+    engine::alpine::check_arch(&config.alpine.arch).map_err(|e| format!("alpine.arch: {}", e))?;
+    engine::alpine::check_version(&config.alpine.version)
+        .map_err(|e| format!("alpine.version: {}", e))?;
 
-    info!("prefetching alpine base image...");
-    engine::alpine::download_base_image(
-        &engine::alpine::VERSION.to_string(),
-        &engine::alpine::ARCH.to_string(),
-    )
-    .await?;
-    info!("prefetching slirp4netns binary...");
-    engine::slirp::download_slirp4netns().await?;
+    // Only we should be able to get at our sockets and containers
+    for dir in [&config.runtime_dir, &config.containers_dir()] {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        check_private_dir(dir, false)?;
+    }
+    fs::create_dir_all(&config.cache_dir)?;
+
+    let path = config.socket();
+    prepare_socket(&path)?;
 
-    let path = Path::new("/tmp/squishd.sock");
-    if path.exists() {
-        fs::remove_file(path)?;
+    // Now that no other squishd can be running, anything left in the state
+    // and runtime directories belonged to one that died
+    let cleaned = engine::journal::clean_orphans();
+    if cleaned.containers > 0 || cleaned.sockets > 0 {
+        info!(
+            "cleaned up after {} orphaned container(s): killed {} process(es), removed {} socket(s)",
+            cleaned.containers, cleaned.processes, cleaned.sockets
+        );
+    }
+    if cleaned.kept > 0 {
+        info!("left {} kept container(s) running", cleaned.kept);
+    }
+    let mut temp_dirs = vec![config.cache_dir.clone()];
+    // Kept containers may still have a rootfs mounted that was since replaced
+    if cleaned.kept == 0 {
+        temp_dirs.push(engine::alpine::rootfs_directory().into());
+    }
+    let temp_files: usize = temp_dirs
+        .iter()
+        .map(|dir| engine::download::clean_temp_files(dir))
+        .sum();
+    if temp_files > 0 {
+        info!(
+            "removed {} unfinished download(s) and replaced file(s)",
+            temp_files
+        );
     }
 
-    let global_state = Arc::new(Mutex::new(ContainerState::new()));
+    if config.offline {
+        // Only say what's missing, so that it can be imported once we're up
+        let (version, arch) = (&config.alpine.version, &config.alpine.arch);
+        if !engine::alpine::is_cached(version, arch) {
+            warn!(
+                "offline, and alpine {} ({}) isn't cached: import it with `squish cache import`",
+                version, arch
+            );
+        }
+        if !engine::slirp::binary().exists() {
+            warn!("offline, and slirp4netns isn't cached: import it with `squish cache import`");
+        }
+    } else if config.prefetch {
+        // Anything that fails here is tried again when a container needs it
+        info!("prefetching alpine base image...");
+        if let Err(e) =
+            engine::alpine::download_base_image(&config.alpine.version, &config.alpine.arch).await
+        {
+            warn!("couldn't prefetch alpine base image: {}", e);
+        }
+        info!("prefetching slirp4netns binary...");
+        if let Err(e) = engine::slirp::download_slirp4netns().await {
+            warn!("couldn't prefetch slirp4netns binary: {}", e);
+        }
+    }
 
-    let clone = global_state.clone();
-    tokio::spawn(engine::containers::reap_children(clone));
+    // Containers are double-forked off by pid1, so become a subreaper in
+    // order to be able to wait on them and learn how they exited.
+    // Safety: PR_SET_CHILD_SUBREAPER takes no pointers.
+    if unsafe { nix::libc::prctl(nix::libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        warn!(
+            "couldn't become a child subreaper, container exit statuses won't be recorded: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    let global_state = Arc::new(Mutex::new(ContainerState::new()));
 
     // Container routes
     let container_create = warp::path!("containers" / "create")
         .and(warp::post())
+        .and(warp::query::<handlers::container::CreateOptions>())
+        .and(auth::manager())
         .and(with_state(global_state.clone()))
         .and(warp::body::bytes().map(|bytes: Bytes| {
             let vec: Vec<u8> = bytes.to_vec();
             let body = String::from_utf8(vec).expect("squishfile not valid string");
-            serde_json::from_str(&*body).expect("squishfile invalid")
+            serde_json::from_str(&body).expect("squishfile invalid")
         }))
         .and_then(handlers::container::create_container);
     let container_list = warp::path!("containers" / "list")
         .and(warp::get())
+        .and(auth::caller())
         .and(with_state(global_state.clone()))
         .and_then(handlers::container::list_containers);
     let container_stop = warp::path!("containers" / "stop" / String)
         .and(warp::post())
+        .and(warp::query::<handlers::container::StopOptions>())
+        .and(auth::manager())
         .and(with_state(global_state.clone()))
         .and_then(handlers::container::stop_container);
+    let containers_stop = warp::path!("containers" / "stop")
+        .and(warp::post())
+        .and(warp::query::<handlers::container::BulkStopOptions>())
+        .and(auth::manager())
+        .and(with_state(global_state.clone()))
+        .and_then(handlers::container::stop_containers);
+    let container_wait = warp::path!("containers" / String / "wait")
+        .and(warp::post())
+        .and(auth::caller())
+        .and(with_state(global_state.clone()))
+        .and_then(handlers::container::wait_container);
+    let container_kill = warp::path!("containers" / String / "kill")
+        .and(warp::post())
+        .and(warp::query::<handlers::container::KillOptions>())
+        .and(auth::manager())
+        .and(with_state(global_state.clone()))
+        .and_then(handlers::container::kill_container);
+    let container_pause = warp::path!("containers" / String / "pause")
+        .and(warp::post())
+        .and(auth::manager())
+        .and(with_state(global_state.clone()))
+        .and_then(handlers::container::pause_container);
+    let container_unpause = warp::path!("containers" / String / "unpause")
+        .and(warp::post())
+        .and(auth::manager())
+        .and(with_state(global_state.clone()))
+        .and_then(handlers::container::unpause_container);
+    let container_restart = warp::path!("containers" / String / "restart")
+        .and(warp::post())
+        .and(auth::manager())
+        .and(with_state(global_state.clone()))
+        .and_then(handlers::container::restart_container);
+    let container_stats = warp::path!("containers" / String / "stats")
+        .and(warp::get())
+        .and(auth::caller())
+        .and(with_state(global_state.clone()))
+        .and_then(handlers::container::container_stats);
+
+    // Cache routes
+    let cache_import = warp::path!("cache" / "import")
+        .and(warp::post())
+        .and(warp::query::<handlers::cache::ImportOptions>())
+        .and(auth::manager())
+        .and(warp::body::content_length_limit(
+            handlers::cache::IMPORT_LIMIT,
+        ))
+        .and(warp::body::bytes())
+        .and_then(handlers::cache::import);
+    let cache_verify = warp::path!("cache" / "verify")
+        .and(warp::post())
+        .and(auth::manager())
+        .and_then(handlers::cache::verify);
+
+    // Event routes
+    let events = warp::path!("events")
+        .and(warp::get())
+        .and(auth::caller())
+        .and(with_state(global_state.clone()))
+        .and_then(handlers::events::stream_events);
 
     // Utility routes
     let status = warp::path!("status")
         .and(warp::get())
+        .and(auth::caller())
+        .and(with_state(global_state.clone()))
         .and_then(handlers::status);
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(auth::caller())
+        .and(with_state(global_state.clone()))
+        .and_then(handlers::metrics);
 
     let log = warp::log("squishd");
     let routes = warp::any()
@@ -89,14 +355,126 @@ async fn main() -> SyncResult<()> {
             container_create
                 .or(container_list)
                 .or(container_stop)
-                .or(status),
+                .or(containers_stop)
+                .or(container_wait)
+                .or(container_kill)
+                .or(container_pause)
+                .or(container_unpause)
+                .or(container_restart)
+                .or(container_stats)
+                .or(cache_import)
+                .or(cache_verify)
+                .or(events)
+                .or(status)
+                .or(metrics),
         )
-        .with(log);
+        .recover(handlers::handle_rejection)
+        .with(log)
+        .with(warp::log::custom(metrics::record_request));
+
+    // Listen for these before serving anything, so that they can't kill
+    // squishd outright.
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
 
-    let listener = UnixListener::bind(path).unwrap();
+    let mut tcp_server = None;
+    if let Some(addr) = config.tcp.listen {
+        let (cert, key, tokens) = match (&config.tcp.cert, &config.tcp.key, &config.tcp.tokens) {
+            (Some(cert), Some(key), Some(tokens)) => (cert, key, tokens),
+            _ => return Err("tcp.listen needs tcp.cert, tcp.key and tcp.tokens to be set".into()),
+        };
+        check_tls_files(cert, key)?;
+        // warp panics in the background if it can't bind, so make sure it can
+        std::net::TcpListener::bind(addr)
+            .map_err(|e| format!("couldn't listen on {}: {}", addr, e))?;
+        auth::load_tokens(tokens)?;
+        let server = warp::serve(routes.clone())
+            .tls()
+            .cert_path(cert)
+            .key_path(key);
+        info!("listening on https://{}", addr);
+        tcp_server = Some(tokio::spawn(server.run(addr)));
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    // Connections are authorised by their peer credentials, so the socket
+    // only has to be closed off when nobody but us is allowed in anyway.
+    let mode = if config.auth.allows_others() {
+        0o666
+    } else {
+        0o600
+    };
+    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+    info!("listening on {}", path.display());
     let incoming = UnixListenerStream::new(listener);
-    warp::serve(routes).run_incoming(incoming).await;
+    // Serve via hyper directly rather than warp::serve, so that each request
+    // can be tagged with who's on the other end of its connection.
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |conn: &UnixStream| {
+        let caller = match conn.peer_cred() {
+            Ok(cred) => {
+                let caller = auth::authorise(&cred);
+                if caller.is_none() {
+                    warn!(
+                        "rejecting requests from uid {} (gid {}, pid {:?})",
+                        cred.uid(),
+                        cred.gid(),
+                        cred.pid()
+                    );
+                }
+                caller
+            }
+            Err(e) => {
+                warn!("couldn't get peer credentials: {}", e);
+                None
+            }
+        };
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                if let Some(caller) = caller {
+                    req.extensions_mut().insert(caller);
+                }
+                service.clone().call(req)
+            }))
+        }
+    });
+    let server = Server::builder(accept::from_stream(incoming)).serve(make_service);
+    let received = tokio::select! {
+        result = server => return result.map_err(|e| e.into()),
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    };
+
+    // Dropping the servers closes their listeners, so nothing new can connect.
+    // Requests that are already in flight, like waits, carry on regardless.
+    info!("received {}, shutting down", received);
+    if let Some(tcp_server) = tcp_server {
+        tcp_server.abort();
+    }
+    if let Err(e) = fs::remove_file(&path) {
+        warn!("couldn't remove socket {}: {}", path.display(), e);
+    }
 
+    if config.keep_containers {
+        let kept = engine::containers::keep_all_containers(&global_state);
+        info!("shut down, leaving {} container(s) running", kept);
+        return Ok(());
+    }
+    let stopped = tokio::select! {
+        stopped = engine::containers::stop_all_containers(&global_state) => stopped,
+        _ = sigterm.recv() => return Err("received SIGTERM again, giving up on stopping containers".into()),
+        _ = sigint.recv() => return Err("received SIGINT again, giving up on stopping containers".into()),
+    };
+    let killed = stopped
+        .iter()
+        .filter(|container| container.signal.as_deref() == Some("SIGKILL"))
+        .count();
+    info!(
+        "shut down, stopped {} container(s) ({} had to be killed)",
+        stopped.len(),
+        killed
+    );
     Ok(())
 }
 
@@ -106,6 +484,82 @@ async fn main() {
     panic!("squishd must be run on a unix-like os!");
 }
 
+/// Makes sure squishd can bind to the socket at `path`: its directory is created
+/// if needed, and a socket left behind by a dead daemon is removed. Refuses to
+/// touch the socket if another daemon is still listening on it.
+fn prepare_socket(path: &Path) -> SyncResult<()> {
+    if let Some(dir) = path.parent() {
+        // Only the directory's owner should be able to reach the socket
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        check_private_dir(dir, config::get().auth.allows_others())?;
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(format!("another squishd is already listening on {}", path.display()).into());
+    }
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Makes sure that a directory squishd keeps sockets in is really its own.
+/// Creating a directory that already exists succeeds whatever its owner and
+/// mode, so one under a shared directory like `/tmp` could have been made
+/// beforehand by someone else, or be a symlink to somewhere they control.
+/// Nobody else may write to it, and unless other users are allowed to connect,
+/// nobody else may look inside it either.
+fn check_private_dir(dir: &Path, shared: bool) -> SyncResult<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    if metadata.file_type().is_symlink() {
+        return Err(format!("{} is a symlink", dir.display()).into());
+    }
+    if !metadata.is_dir() {
+        return Err(format!("{} isn't a directory", dir.display()).into());
+    }
+    if metadata.uid() != getuid().as_raw() {
+        return Err(format!(
+            "{} is owned by uid {}, not squishd's own user",
+            dir.display(),
+            metadata.uid()
+        )
+        .into());
+    }
+    let forbidden = if shared { 0o022 } else { 0o077 };
+    let mode = metadata.mode() & 0o777;
+    if mode & forbidden != 0 {
+        return Err(format!(
+            "{} is accessible by other users (mode {:o})",
+            dir.display(),
+            mode
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Makes sure that the TLS certificate chain and private key can be loaded,
+/// since warp panics in the background if they can't.
+fn check_tls_files(cert: &Path, key: &Path) -> SyncResult<()> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(fs::File::open(cert)?))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cert.display()).into());
+    }
+    let keys = rustls_pemfile::read_all(&mut BufReader::new(fs::File::open(key)?))?;
+    let has_key = keys.iter().any(|item| {
+        matches!(
+            item,
+            rustls_pemfile::Item::RSAKey(_) | rustls_pemfile::Item::PKCS8Key(_)
+        )
+    });
+    if !has_key {
+        return Err(format!("no RSA or PKCS#8 private key found in {}", key.display()).into());
+    }
+    Ok(())
+}
+
 fn with_state<T: Clone + Send + Sync>(
     state: T,
 ) -> impl Filter<Extract = (T,), Error = std::convert::Infallible> + Clone {
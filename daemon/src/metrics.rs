@@ -0,0 +1,122 @@
+use std::time::Instant;
+
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    TextEncoder,
+};
+
+lazy_static! {
+    pub static ref CONTAINERS: IntGaugeVec = prometheus::register_int_gauge_vec!(
+        Opts::new(
+            "squish_containers",
+            "Containers known to squishd, by status"
+        ),
+        &["status"]
+    )
+    .unwrap();
+    pub static ref CREATE_DURATION: Histogram =
+        prometheus::register_histogram!(HistogramOpts::new(
+            "squish_container_create_duration_seconds",
+            "Time taken to successfully spawn a container, from start to finish"
+        )
+        .buckets(DURATION_BUCKETS.to_vec()))
+        .unwrap();
+    pub static ref SPAWN_PHASE_DURATION: HistogramVec = prometheus::register_histogram_vec!(
+        HistogramOpts::new(
+            "squish_container_spawn_phase_duration_seconds",
+            "Time taken by each phase of successfully spawning a container"
+        )
+        .buckets(DURATION_BUCKETS.to_vec()),
+        &["phase"]
+    )
+    .unwrap();
+    pub static ref REAPER_CLEANUPS: IntCounter = prometheus::register_int_counter!(
+        "squish_reaper_cleanups_total",
+        "Exited containers cleaned up by the reaper"
+    )
+    .unwrap();
+    pub static ref CLEANUP_FAILURES: IntCounter = prometheus::register_int_counter!(
+        "squish_cleanup_failures_total",
+        "Failed attempts to clean up after a container"
+    )
+    .unwrap();
+    pub static ref DOWNLOAD_BYTES: IntCounterVec = prometheus::register_int_counter_vec!(
+        "squish_download_bytes_total",
+        "Bytes downloaded, by artifact",
+        &["artifact"]
+    )
+    .unwrap();
+    pub static ref CACHE_LOOKUPS: IntCounterVec = prometheus::register_int_counter_vec!(
+        "squish_cache_lookups_total",
+        "Cache lookups for downloadable artifacts, by artifact and result",
+        &["artifact", "result"]
+    )
+    .unwrap();
+    pub static ref API_REQUESTS: IntCounterVec = prometheus::register_int_counter_vec!(
+        "squish_api_requests_total",
+        "API requests, by route and response status",
+        &["route", "status"]
+    )
+    .unwrap();
+}
+
+/// Container spawns are expected to take milliseconds, so the default buckets
+/// (which go up to 10s) are too coarse.
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Records a cache hit or miss for an artifact.
+pub fn cache_lookup(artifact: &str, hit: bool) {
+    CACHE_LOOKUPS
+        .with_label_values(&[artifact, if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+/// Records how long a phase of spawning a container took, now that it has
+/// succeeded. Failed phases aren't recorded, as failing early would otherwise
+/// pass for being fast.
+pub fn spawn_phase(phase: &str, started: Instant) {
+    SPAWN_PHASE_DURATION
+        .with_label_values(&[phase])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+/// Counts an API request. Used with `warp::log::custom`, which hands over the
+/// `Info` by value.
+#[allow(clippy::needless_pass_by_value)]
+pub fn record_request(info: warp::log::Info) {
+    API_REQUESTS
+        .with_label_values(&[&route_label(info.path()), info.status().as_str()])
+        .inc();
+}
+
+/// What can be done to a single container, as in `/containers/:id/<action>`.
+const CONTAINER_ACTIONS: &[&str] = &["kill", "pause", "unpause", "restart", "stats", "wait"];
+
+/// Turns a request path into a route, so that container ids don't end up in
+/// label values. eg. `/containers/abc123/wait` becomes `/containers/:id/wait`.
+/// Paths that aren't routes are all `other`, since clients could otherwise
+/// make up as many label values as they like.
+fn route_label(path: &str) -> String {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["containers", action @ ("create" | "list" | "stop")] => format!("/containers/{}", action),
+        ["containers", "stop", _] => "/containers/stop/:id".to_string(),
+        ["containers", _, action] if CONTAINER_ACTIONS.contains(action) => {
+            format!("/containers/:id/{}", action)
+        }
+        ["cache", action @ ("import" | "verify")] => format!("/cache/{}", action),
+        [route @ ("events" | "status" | "metrics")] => format!("/{}", route),
+        _ => "other".to_string(),
+    }
+}
+
+/// Renders every metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("couldn't encode metrics!?");
+    String::from_utf8(buffer).expect("metrics weren't utf-8!?")
+}
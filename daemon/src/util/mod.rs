@@ -1,5 +1,10 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
 use std::{error::Error, fmt::Display};
 
+use libsquish::signals;
+use nix::sys::signal::Signal;
+
 #[derive(Debug)]
 pub enum SquishError {
     GenericError(Box<dyn std::error::Error + Send + Sync>),
@@ -9,14 +14,152 @@ pub enum SquishError {
     AlpineManifestInvalid,
     AlpineManifestMissing,
     AlpineManifestFileMissing,
+    AlpineManifestChecksumMissing,
+    /// Something whose digest didn't match, along with the expected and
+    /// actual digests.
+    ChecksumMismatch(String, String, String),
+    /// Something that would have to be downloaded, while squishd is offline.
+    NotCached(String),
+    InvalidImport(String),
+    /// Something that isn't available for an architecture, and the
+    /// architecture.
+    NoArtefactForArch(String, String),
+    InvalidAlpineVersion(String),
+    /// An architecture that can't run natively on the host, and the host's.
+    CrossArch(String, String),
+
+    InvalidSignal(String),
+    InvalidFilter(String),
+    NothingSelected,
+    NoSuchContainer(String),
+    /// A container reference that matched several containers, along with
+    /// each of them.
+    AmbiguousReference(String, Vec<String>),
+    InvalidReplicas(usize),
+    InvalidName(String),
+    NameInUse(String),
+    ContainerNotRunning(String),
+    ContainerNotPaused(String),
+    /// A container that's still running, or whose slirp4netns still is, after
+    /// it was killed.
+    ContainerDidntStop(String),
+
+    Forbidden,
+    ReadOnly,
+    /// A layer path that the caller isn't allowed to mount.
+    ForbiddenPath(String),
 }
 
 impl Display for SquishError {
-    fn fmt(&self, _: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
-        todo!()
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            SquishError::GenericError(e) => write!(f, "{}", e),
+            SquishError::SlirpSocketCouldntBeFound => {
+                write!(f, "slirp4netns socket couldn't be found")
+            }
+            SquishError::AlpineManifestInvalid => write!(f, "alpine manifest invalid"),
+            SquishError::AlpineManifestMissing => write!(f, "alpine manifest missing minirootfs"),
+            SquishError::AlpineManifestFileMissing => write!(f, "alpine manifest missing file"),
+            SquishError::AlpineManifestChecksumMissing => {
+                write!(f, "alpine manifest missing sha256 and sha512")
+            }
+            SquishError::ChecksumMismatch(what, expected, actual) => write!(
+                f,
+                "checksum mismatch for {}: expected {}, got {}",
+                what, expected, actual
+            ),
+            SquishError::NotCached(what) => write!(
+                f,
+                "{} isn't cached and squishd is offline, import it with `squish cache import`",
+                what
+            ),
+            SquishError::InvalidImport(reason) => write!(f, "can't import: {}", reason),
+            SquishError::NoArtefactForArch(what, arch) => {
+                write!(f, "no {} is available for {}", what, arch)
+            }
+            SquishError::InvalidAlpineVersion(version) => write!(
+                f,
+                "invalid alpine version: {} (expected a release branch like 3.14, or edge)",
+                version
+            ),
+            SquishError::CrossArch(arch, host) => write!(
+                f,
+                "can't run {} containers on this {} host, only native architectures are supported",
+                arch, host
+            ),
+            SquishError::InvalidSignal(signal) => write!(f, "invalid signal: {}", signal),
+            SquishError::InvalidFilter(filter) => write!(f, "invalid filter: {}", filter),
+            SquishError::NothingSelected => {
+                write!(f, "no containers selected, pass all=true or a filter")
+            }
+            SquishError::NoSuchContainer(reference) => write!(f, "no such container: {}", reference),
+            SquishError::AmbiguousReference(reference, candidates) => write!(
+                f,
+                "{} matches more than one container: {}",
+                reference,
+                candidates.join(", ")
+            ),
+            SquishError::InvalidReplicas(replicas) => write!(
+                f,
+                "invalid replica count: {} (must be 1 to {})",
+                replicas,
+                crate::handlers::container::MAX_REPLICAS
+            ),
+            SquishError::InvalidName(name) => write!(
+                f,
+                "invalid container name: {} (names may only contain letters, digits, '_', '.' and '-', and must start with a letter or digit)",
+                name
+            ),
+            SquishError::NameInUse(name) => write!(f, "container name already in use: {}", name),
+            SquishError::ContainerNotRunning(id) => write!(f, "container not running: {}", id),
+            SquishError::ContainerNotPaused(id) => write!(f, "container not paused: {}", id),
+            SquishError::ContainerDidntStop(id) => {
+                write!(f, "container didn't stop after being killed: {}", id)
+            }
+            SquishError::Forbidden => write!(f, "not allowed to use squishd"),
+            SquishError::ReadOnly => write!(f, "token only allows reading"),
+            SquishError::ForbiddenPath(path) => write!(f, "not allowed to mount {}", path),
+        }
+    }
+}
+
+impl SquishError {
+    /// Wraps an engine error, keeping it as-is if it's already a `SquishError`
+    /// so that it gets the right status code.
+    pub fn from_boxed(error: Box<dyn Error + Send + Sync>) -> SquishError {
+        match error.downcast::<SquishError>() {
+            Ok(error) => *error,
+            Err(error) => SquishError::GenericError(error),
+        }
     }
 }
 
 impl warp::reject::Reject for SquishError {}
 
 impl Error for SquishError {}
+
+/// Parses a signal from its name or number. Names may be given with or without
+/// the `SIG` prefix, so `SIGHUP`, `HUP`, `hup` and `1` are all equivalent.
+fn parse_signal(signal: &str) -> Result<Signal, SquishError> {
+    if let Ok(number) = signal.parse::<i32>() {
+        return Signal::try_from(number).map_err(|_| SquishError::InvalidSignal(signal.into()));
+    }
+    let name = signal.to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Signal::from_str(&name).map_err(|_| SquishError::InvalidSignal(signal.into()))
+}
+
+/// Parses a signal to send to a workload, like `parse_signal`. Signals that
+/// pid1 doesn't pass on are refused, as they'd never reach the workload.
+pub fn parse_workload_signal(signal: &str) -> Result<Signal, SquishError> {
+    let parsed = parse_signal(signal)?;
+    if signals::reaches_workload(parsed) {
+        Ok(parsed)
+    } else {
+        Err(SquishError::InvalidSignal(signal.into()))
+    }
+}
@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+
+/// A container lifecycle event, as emitted on squishd's `/events` stream. The
+/// stream is newline-delimited JSON, with one event per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContainerEvent {
+    #[serde(flatten)]
+    pub kind: EventKind,
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u128,
+    pub id: String,
+    pub name: String,
+    /// The container's pid. This is absent for events emitted before the
+    /// container has been spawned.
+    pub pid: Option<i32>,
+    /// The uid of the user that created the container.
+    pub owner: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The container was accepted by the daemon and is about to be spawned.
+    Create,
+    /// pid1 and slirp4netns are up.
+    Start,
+    /// A port forward was added to the container's slirp4netns instance.
+    PortForwardAdded { host: u16, container: u16 },
+    /// The container's process exited on its own.
+    Exit {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    /// A dead container's files and slirp4netns instance were cleaned up.
+    Reaped,
+    /// The container was stopped via the API.
+    Stop,
+}
+
+impl ContainerEvent {
+    /// Builds a new event, timestamped with the current time.
+    pub fn new<S: Into<String>>(
+        kind: EventKind,
+        id: S,
+        name: S,
+        pid: Option<i32>,
+        owner: u32,
+    ) -> Self {
+        ContainerEvent {
+            kind,
+            timestamp: crate::now().expect("system clock is before the UNIX epoch!?"),
+            id: id.into(),
+            name: name.into(),
+            pid,
+            owner,
+        }
+    }
+}
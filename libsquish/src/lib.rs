@@ -1,9 +1,12 @@
 #![warn(clippy::needless_pass_by_value)]
 
+pub mod event;
+pub mod paths;
+pub mod signals;
 pub mod squishfile;
 
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::io::ErrorKind;
 use std::time::SystemTime;
 
 use serde::{Deserialize, Serialize};
@@ -11,13 +14,191 @@ use serde::{Deserialize, Serialize};
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 pub type SyncResult<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
-/// A currently-running container. This is effectively a three-typle of the
-/// container's id, name, and pid.
+/// A container tracked by the daemon. This is effectively the container's id,
+/// name, pid, owner and labels, along with how it ended if it's no longer
+/// running.
 #[derive(Serialize, Deserialize)]
 pub struct RunningContainer {
     pub id: String,
     pub name: String,
     pub pid: i32,
+    /// The uid of the user that created the container.
+    pub owner: u32,
+    pub status: ContainerStatus,
+    pub exit: Option<ExitStatus>,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A container that was created via the API, along with the host ports that
+/// were forwarded to it.
+#[derive(Serialize, Deserialize)]
+pub struct CreatedContainer {
+    pub id: String,
+    pub name: String,
+    pub ports: Vec<squishfile::Port>,
+}
+
+/// A container that was stopped via the API. `signal` is the signal that
+/// finally ended the container, and is absent if the container had already
+/// exited on its own.
+#[derive(Serialize, Deserialize)]
+pub struct StoppedContainer {
+    pub id: String,
+    pub name: String,
+    pub signal: Option<String>,
+    pub exit: Option<ExitStatus>,
+}
+
+/// A container that was sent a signal via the API.
+#[derive(Serialize, Deserialize)]
+pub struct SignalledContainer {
+    pub id: String,
+    pub name: String,
+    pub signal: String,
+}
+
+/// A snapshot of a container's resource usage, covering every process in it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContainerStats {
+    pub id: String,
+    pub name: String,
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u128,
+    /// Total user and system CPU time used, in milliseconds.
+    pub cpu_time_ms: u64,
+    /// Resident memory, in bytes.
+    pub memory_bytes: u64,
+    pub processes: usize,
+    pub open_fds: usize,
+    /// Network bytes received and sent by the container.
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// What the daemon reports about itself from `/status`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaemonStatus {
+    pub version: String,
+    /// The host's architecture, in Alpine's naming.
+    pub arch: String,
+    pub uptime_secs: u64,
+    pub socket: String,
+    pub state_dir: String,
+    pub cache_dir: String,
+    /// Every Alpine rootfs in the cache.
+    pub alpine: Vec<CachedRootfs>,
+    /// The version reported by the slirp4netns binary, if it could be run.
+    pub slirp4netns: Option<String>,
+    pub features: KernelFeatures,
+    /// How many containers the caller can see, including exited ones.
+    pub containers: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CachedRootfs {
+    pub version: String,
+    pub arch: String,
+}
+
+/// What `squish cache import` put into the cache.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase", tag = "kind")]
+pub enum ImportedFile {
+    Alpine(CachedRootfs),
+    Slirp4netns { version: String },
+}
+
+/// A cached file that `squish cache verify` checked.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VerifiedFile {
+    pub path: String,
+    pub status: VerifyStatus,
+    /// Where the file was moved to if it didn't match.
+    pub quarantined: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyStatus {
+    Ok,
+    Mismatch,
+    /// No digest was recorded when the file was cached, so there's nothing to
+    /// check it against.
+    Unrecorded,
+    Unreadable,
+}
+
+impl std::fmt::Display for VerifyStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyStatus::Ok => write!(f, "ok"),
+            VerifyStatus::Mismatch => write!(f, "mismatch"),
+            VerifyStatus::Unrecorded => write!(f, "unrecorded"),
+            VerifyStatus::Unreadable => write!(f, "unreadable"),
+        }
+    }
+}
+
+/// Kernel features that squishd relies on, or makes use of when available.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct KernelFeatures {
+    /// Unprivileged users can create user namespaces, which containers need.
+    pub unprivileged_userns: bool,
+    /// squishd was delegated a cgroup v2 subtree, so containers get cgroups
+    /// of their own.
+    pub cgroup_delegation: bool,
+    /// Container exits are noticed through pidfds.
+    pub pidfd: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerStatus {
+    Running,
+    Paused,
+    Stopping,
+    Exited,
+}
+
+impl std::fmt::Display for ContainerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerStatus::Running => write!(f, "running"),
+            ContainerStatus::Paused => write!(f, "paused"),
+            ContainerStatus::Stopping => write!(f, "stopping"),
+            ContainerStatus::Exited => write!(f, "exited"),
+        }
+    }
+}
+
+/// How a container's workload ended. Exactly one of `code` and `signal` is
+/// set: `code` if the workload exited normally, `signal` if it was killed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitStatus {
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    /// Milliseconds since the UNIX epoch.
+    pub exited_at: u128,
+}
+
+impl ExitStatus {
+    /// The exit code a shell would report for this status, ie. the exit code
+    /// itself, or 128 + the signal number.
+    pub fn shell_code(&self) -> i32 {
+        match (self.code, self.signal) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(&self).map_err(|e| e.into())
+    }
+
+    pub fn from_json<'a, S: Into<&'a str>>(json: S) -> Result<Self> {
+        serde_json::from_str(json.into()).map_err(|e| e.into())
+    }
 }
 
 /// Returns the current time in milliseconds since the UNIX epoch.
@@ -28,8 +209,5 @@ pub fn now() -> Result<u128> {
 }
 
 pub fn err<T, S: Into<String>>(reason: S) -> Result<T> {
-    Err(Box::new(std::io::Error::new(
-        ErrorKind::Other,
-        reason.into(),
-    )))
+    Err(Box::new(std::io::Error::other(reason.into())))
 }
@@ -0,0 +1,52 @@
+//! Default locations for squish's files, following the XDG base directory
+//! spec where it has something to say.
+
+use std::env;
+use std::path::PathBuf;
+
+use nix::unistd::getuid;
+
+/// The directory squish keeps runtime files such as the API socket in. This is
+/// `$XDG_RUNTIME_DIR/squish`, falling back to a per-user directory under
+/// `/tmp` if `XDG_RUNTIME_DIR` isn't set. Since anyone can create that one
+/// first, squishd refuses to use it unless it's a directory of its own that
+/// nobody else can get into.
+pub fn runtime_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("squish"),
+        _ => env::temp_dir().join(format!("squish-{}", getuid())),
+    }
+}
+
+/// Where squishd listens, and the CLI connects to, by default.
+pub fn default_socket_path() -> PathBuf {
+    runtime_dir().join("squishd.sock")
+}
+
+/// The directory squish's configuration files live in. This is
+/// `$XDG_CONFIG_HOME/squish`, defaulting to `~/.config/squish`.
+pub fn config_dir() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config").join("squish")
+}
+
+/// The directory squishd keeps container state in. This is
+/// `$XDG_STATE_HOME/squish`, defaulting to `~/.local/state/squish`.
+pub fn state_dir() -> PathBuf {
+    xdg_dir("XDG_STATE_HOME", ".local/state").join("squish")
+}
+
+/// The directory squishd caches downloads such as Alpine rootfs images in.
+/// This is `$XDG_CACHE_HOME/squish`, defaulting to `~/.cache/squish`.
+pub fn cache_dir() -> PathBuf {
+    xdg_dir("XDG_CACHE_HOME", ".cache").join("squish")
+}
+
+fn xdg_dir(var: &str, home_fallback: &str) -> PathBuf {
+    match env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("/"))
+            .join(home_fallback),
+    }
+}
@@ -0,0 +1,35 @@
+use nix::sys::signal::Signal;
+
+/// The signals that pid1 passes on to the workload. As pid1 is the init
+/// process of the container's pid namespace, the kernel drops any signal sent
+/// to it from outside that it doesn't have a handler for, so these are the
+/// only ones that a workload can be sent, plus SIGKILL, which takes down the
+/// whole container.
+///
+/// Signals that pid1 could raise on itself, such as SIGPIPE, SIGCHLD or the
+/// fault signals, aren't forwarded, as pid1 can't tell whether they were meant
+/// for the workload.
+pub const FORWARDED_SIGNALS: &[Signal] = &[
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGALRM,
+    Signal::SIGVTALRM,
+    Signal::SIGPROF,
+    Signal::SIGWINCH,
+    Signal::SIGURG,
+    Signal::SIGIO,
+    Signal::SIGPWR,
+    Signal::SIGCONT,
+    Signal::SIGTSTP,
+    Signal::SIGTTIN,
+    Signal::SIGTTOU,
+];
+
+/// Whether the given signal reaches the workload when sent to its pid1.
+pub fn reaches_workload(signal: Signal) -> bool {
+    signal == Signal::SIGKILL || FORWARDED_SIGNALS.contains(&signal)
+}
@@ -6,12 +6,19 @@ use std::path::Path;
 use derive_getters::Getters;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize, Getters, Debug)]
+#[derive(Deserialize, Serialize, Getters, Debug, Clone)]
 pub struct Squishfile {
     run: Run,
     layers: BTreeMap<String, LayerSpec>,
     env: HashMap<String, String>,
     ports: Vec<Port>,
+    /// Arbitrary key-value metadata attached to the container.
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    /// The architecture to run as, in Alpine's naming, eg. `"aarch64"`.
+    /// Defaults to squishd's `alpine.arch`, which is the host's.
+    #[serde(default)]
+    arch: Option<String>,
 }
 
 impl Squishfile {
@@ -64,6 +71,22 @@ impl Squishfile {
         resolved.iter().for_each(|(k, v)| self.update_layer(k, v));
     }
 
+    /// Sets a label, replacing any existing value.
+    pub fn set_label<S: Into<String>>(&mut self, key: S, value: S) {
+        self.labels.insert(key.into(), value.into());
+    }
+
+    /// Replaces the host path a layer is mounted from, if there's such a layer.
+    pub fn set_layer_path<S: Into<String>>(&mut self, layer: &str, path: S) {
+        if let Some(spec) = self.layers.get_mut(layer) {
+            spec.path = Some(path.into());
+        }
+    }
+
+    pub fn ports_mut(&mut self) -> &mut Vec<Port> {
+        &mut self.ports
+    }
+
     pub fn to_json(&self) -> Result<String, Box<dyn Error>> {
         serde_json::to_string(&self).map_err(|e| e.into())
     }
@@ -89,26 +112,48 @@ pub struct LayerSpec {
     rw: Option<bool>,
 }
 
-#[derive(Deserialize, Serialize, Getters, Debug)]
+#[derive(Deserialize, Serialize, Getters, Debug, Clone)]
 pub struct Run {
     command: String,
     args: Vec<String>,
+    /// The signal sent to the workload when the container is stopped, eg.
+    /// `"SIGINT"`. Defaults to SIGTERM. This has to be one of
+    /// `signals::FORWARDED_SIGNALS`, or SIGKILL.
+    stop_signal: Option<String>,
+    /// How many seconds to wait after `stop_signal` before escalating to
+    /// SIGKILL. Defaults to squishd's default stop timeout.
+    stop_timeout: Option<u64>,
 }
 
-#[derive(Deserialize, Serialize, Getters, Debug)]
+#[derive(Deserialize, Serialize, Getters, Debug, Clone)]
 pub struct Port {
     container: u16,
     host: u16,
     protocol: PortProtocol,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+impl Port {
+    pub fn set_host(&mut self, host: u16) {
+        self.host = host;
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum PortProtocol {
     Tcp,
     Udp,
 }
 
+impl std::fmt::Display for PortProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortProtocol::Tcp => write!(f, "tcp"),
+            PortProtocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
 pub fn parse_str<'a, T: Into<&'a str>>(squishfile: T) -> Result<Squishfile, Box<dyn Error>> {
     let mut parsed: toml::Value = toml::from_str(squishfile.into())?;
     let table = match parsed.as_table_mut() {
@@ -123,6 +168,14 @@ pub fn parse_str<'a, T: Into<&'a str>>(squishfile: T) -> Result<Squishfile, Box<
         Some(env) => env.clone().try_into()?,
         None => HashMap::new(),
     };
+    let labels: BTreeMap<String, String> = match table.get("labels") {
+        Some(labels) => labels.clone().try_into()?,
+        None => BTreeMap::new(),
+    };
+    let arch = match table.get("arch") {
+        Some(arch) => Some(arch.clone().try_into::<String>()?),
+        None => None,
+    };
     let ports: Vec<Port> = match table.get("ports") {
         Some(ports) => ports
             .as_array()
@@ -153,6 +206,8 @@ pub fn parse_str<'a, T: Into<&'a str>>(squishfile: T) -> Result<Squishfile, Box<
         run,
         env,
         ports,
+        labels,
+        arch,
     })
 }
 
@@ -1,12 +1,16 @@
-use std::fs::{self, OpenOptions};
+mod signals;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
 use std::os::unix::io::IntoRawFd;
+use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
-use std::process;
+use std::process::{self, Command, Stdio};
 
 use libsquish::squishfile::{LayerSpec, Squishfile};
-use libsquish::Result;
+use libsquish::{ExitStatus, Result};
 use nix::mount::{mount, MsFlags};
-use nix::unistd::{chdir, chroot, close, dup, dup2};
+use nix::unistd::{chdir, chroot, close, dup2};
 
 pub struct Engine<'a> {
     squishfile: &'a Squishfile,
@@ -37,27 +41,14 @@ impl<'a> Engine<'a> {
         // Set up container rootfs
         fs::create_dir_all(&self.container_rootfs_path).expect("couldn't create rootfs directory!");
 
-        // redirect stdout/err
-        let stdout_dup = dup(1)?;
-        let stderr_dup = dup(2)?;
-        close(1)?;
-        close(2)?;
-
-        let stdout_log = fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(format!("{}/stdout.log", &self.container_path))?;
-        let stdout_log_fd = stdout_log.into_raw_fd();
-        let stderr_log = fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(format!("{}/stderr.log", &self.container_path))?;
-        let stderr_log_fd = stderr_log.into_raw_fd();
-
-        dup2(stdout_log_fd, stdout_dup)?;
-        dup2(stderr_log_fd, stderr_dup)?;
-        close(stdout_dup)?;
-        close(stderr_dup)?;
+        // Redirect our own stdout/err into setup.log. The workload's stdio is
+        // wired up separately in `run_container`, so that setup chatter
+        // doesn't end up mixed in with the workload's logs.
+        let setup_log = self.create_file("setup.log")?;
+        let setup_log_fd = setup_log.into_raw_fd();
+        dup2(setup_log_fd, 1)?;
+        dup2(setup_log_fd, 2)?;
+        close(setup_log_fd)?;
 
         // Bindmount rootfs ro
         self.bind_mount(
@@ -116,7 +107,15 @@ impl<'a> Engine<'a> {
         Ok(self)
     }
 
-    pub fn run_container(&self) -> Result<()> {
+    /// Runs the workload to completion, returning the exit code that pid1
+    /// should exit with.
+    pub fn run_container(&self) -> Result<i32> {
+        // Open the workload's logs and exit status file before chrooting, as
+        // the container path isn't reachable from inside the rootfs.
+        let stdout_log = self.create_file("stdout.log")?;
+        let stderr_log = self.create_file("stderr.log")?;
+        let mut exit_file = self.create_file("exit.json")?;
+
         // chroot!
         chroot(self.container_rootfs_path.as_str()).expect("couldn't chroot!?");
         chdir("/").expect("couldn't chdir to /!?");
@@ -132,12 +131,15 @@ impl<'a> Engine<'a> {
             }
         }
 
-        self.run_in_container()?;
+        let status = self.run_in_container(stdout_log, stderr_log)?;
+        // The daemon picks this up when it reaps us, since our own exit code
+        // can't tell it whether the workload was killed by a signal.
+        exit_file.write_all(status.to_json()?.as_bytes())?;
         println!(">> done!");
-        Ok(())
+        Ok(status.shell_code())
     }
 
-    fn run_in_container(&self) -> Result<()> {
+    fn run_in_container(&self, stdout_log: File, stderr_log: File) -> Result<ExitStatus> {
         println!(">> inside the container!");
         println!(">> i am {}", process::id());
         println!(
@@ -146,12 +148,24 @@ impl<'a> Engine<'a> {
             self.squishfile.run().args()
         );
 
-        std::process::Command::new(self.squishfile.run().command())
+        signals::catch_signals()?;
+        // The workload writes straight into the log files as it runs, rather
+        // than having its output buffered up by us.
+        let mut workload = Command::new(self.squishfile.run().command())
             .envs(self.squishfile.env())
             .args(self.squishfile.run().args())
-            .output()
-            .unwrap();
-        Ok(())
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(stdout_log))
+            .stderr(Stdio::from(stderr_log))
+            .spawn()?;
+        signals::forward_signals_to(workload.id())?;
+        let status = workload.wait()?;
+        println!(">> workload exited: {}", status);
+        Ok(ExitStatus {
+            code: status.code(),
+            signal: status.signal(),
+            exited_at: libsquish::now()?,
+        })
     }
 
     fn bind_mount_layer<TO>(
@@ -231,6 +245,14 @@ impl<'a> Engine<'a> {
         Ok(())
     }
 
+    fn create_file(&self, name: &str) -> Result<File> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(format!("{}/{}", self.container_path, name))?;
+        Ok(file)
+    }
+
     fn bind_mount_dev(&self, dev: &'static str, target: &str) -> Result<()> {
         println!(">> bindmount dev {} -> {}", dev, target);
         mount(Some(dev), target, Some(""), MsFlags::MS_BIND, Some(""))?;
@@ -251,7 +273,12 @@ impl<'a> Engine<'a> {
     }
 
     fn touch(&self, path: &Path) -> Result<()> {
-        match OpenOptions::new().create(true).write(true).open(path) {
+        match OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)
+        {
             Ok(_) => Ok(()),
             Err(e) => Err(Box::new(e)),
         }
@@ -0,0 +1,56 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+use libsquish::signals::FORWARDED_SIGNALS;
+use libsquish::Result;
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::Pid;
+
+static WORKLOAD_PID: AtomicI32 = AtomicI32::new(0);
+/// Signals that arrived before the workload was spawned, as a bitmask of
+/// signal numbers.
+static PENDING_SIGNALS: AtomicU64 = AtomicU64::new(0);
+
+extern "C" fn forward_signal(signal: nix::libc::c_int) {
+    let pid = WORKLOAD_PID.load(Ordering::SeqCst);
+    if pid > 0 {
+        if let Ok(signal) = Signal::try_from(signal) {
+            // kill(2) is async-signal-safe, and there's nobody to report a
+            // failure to from in here anyway.
+            let _ = kill(Pid::from_raw(pid), signal);
+        }
+    } else {
+        PENDING_SIGNALS.fetch_or(1 << signal, Ordering::SeqCst);
+    }
+}
+
+/// Catches every signal in `FORWARDED_SIGNALS`. This has to happen before the
+/// workload is spawned, as the kernel drops any that arrive while pid1 has no
+/// handler for them; they're held until `forward_signals_to` is called.
+pub fn catch_signals() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(forward_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in FORWARDED_SIGNALS {
+        // Safety: The handler only touches atomics and calls kill(2).
+        unsafe { sigaction(*signal, &action)? };
+    }
+    Ok(())
+}
+
+/// Forwards every signal caught by `catch_signals` on to the workload with the
+/// given pid, starting with any that arrived before it was spawned.
+pub fn forward_signals_to(workload: u32) -> Result<()> {
+    // Anything that arrives once the pid is stored goes straight through the
+    // handler instead, so nothing is sent twice or missed
+    WORKLOAD_PID.store(workload as i32, Ordering::SeqCst);
+    let pending = PENDING_SIGNALS.swap(0, Ordering::SeqCst);
+    for signal in FORWARDED_SIGNALS {
+        if pending & (1 << *signal as i32) != 0 {
+            kill(Pid::from_raw(workload as i32), *signal)?;
+        }
+    }
+    Ok(())
+}
@@ -9,6 +9,7 @@ extern crate tokio;
 
 mod engine;
 
+use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::os::unix::io::FromRawFd;
@@ -17,6 +18,7 @@ use clap::{Arg, Command};
 use libsquish::squishfile::Squishfile;
 use libsquish::Result;
 use nix::sched::{clone, CloneFlags};
+use nix::sys::signal::Signal;
 use rlimit::Resource;
 
 fn main() -> Result<()> {
@@ -49,8 +51,20 @@ fn main() -> Result<()> {
                 .required(true)
                 .help("squishfile memfd to run from"),
         )
+        .arg(
+            Arg::new("cgroup")
+                .long("cgroup")
+                .takes_value(true)
+                .help("path to the container's cgroup, if it has one"),
+        )
         .get_matches();
 
+    // Join the container's cgroup before cloning, so that the container and
+    // everything it spawns ends up in it.
+    if let Some(cgroup) = matches.value_of("cgroup") {
+        fs::write(format!("{}/cgroup.procs", cgroup), "0")?;
+    }
+
     let squishfile_memfd: i32 = matches
         .value_of("squishfile-memfd")
         .unwrap()
@@ -99,7 +113,7 @@ fn spawn_container(
     let callback = move || {
         let engine = engine::Engine::new(&squishfile, &rootfs, &path, &container_id);
         match engine.setup_container().unwrap().run_container() {
-            Ok(_) => 0,
+            Ok(code) => code as isize,
             _ => 1,
         }
     };
@@ -129,7 +143,9 @@ fn spawn_container(
             | CloneFlags::CLONE_NEWNET
             | CloneFlags::CLONE_NEWUSER
             | CloneFlags::CLONE_NEWCGROUP,
-        None,
+        // squishd is a subreaper, so it ends up as our parent once we exit.
+        // Make sure it actually gets told about it.
+        Some(Signal::SIGCHLD as i32),
     )?;
     if (pid.as_raw() as i32) == -1 {
         println!("clone error");
@@ -0,0 +1,14 @@
+#!/usr/bin/env bash
+
+# 008-exit-status-is-recorded
+# Assert that `squish wait` blocks until the container exits, and exits with
+# the container's exit code.
+# SQUISHFILE_OVERRIDE=./test/squishfiles/008-squishfile-exit-code.toml
+
+CONTAINER_ID=$(cargo -q run -p cli -- ps | grep -v "ID" | awk '{print $1}')
+cargo -q run -p cli -- wait $CONTAINER_ID
+EXIT_CODE=$?
+if [ $EXIT_CODE -ne 42 ]; then
+  echo "Expected exit code 42, got $EXIT_CODE"
+  exit 1
+fi
@@ -0,0 +1,51 @@
+#!/usr/bin/env bash
+
+# 009-file-mirror-works
+# Assert that a squishd pointed at a local file:// mirror fetches, verifies and
+# extracts Alpine from it, without going anywhere near the network.
+
+# The host's architecture, in Alpine's naming, as squishd works it out
+case "$(uname -m)" in
+  i386|i486|i586|i686) ARCH=x86 ;;
+  arm64) ARCH=aarch64 ;;
+  armv7l|armv8l) ARCH=armv7 ;;
+  armv6l) ARCH=armhf ;;
+  *) ARCH=$(uname -m) ;;
+esac
+
+DIR=$(mktemp -d)
+trap 'kill $DAEMON 2> /dev/null; rm -rf "$DIR"' EXIT
+mkdir -p "$DIR/cache" "$DIR/mirror/v3.14/releases"
+touch "$DIR/squishd.toml"
+# Pretend slirp4netns is cached, so that only Alpine gets prefetched
+touch "$DIR/cache/slirp4netns-$ARCH"
+# The fixture rootfs has no binaries in it, so it'll do for any architecture
+cp -r test/fixtures/mirror/v3.14/releases/x86_64 "$DIR/mirror/v3.14/releases/$ARCH"
+
+./target/debug/daemon \
+  --config "$DIR/squishd.toml" \
+  --socket "$DIR/squishd.sock" \
+  --runtime-dir "$DIR/run" \
+  --state-dir "$DIR/state" \
+  --cache-dir "$DIR/cache" \
+  --alpine-version 3.14 \
+  --alpine-arch "$ARCH" \
+  --alpine-mirror "file://$DIR/mirror" > "$DIR/daemon.log" 2>&1 &
+DAEMON=$!
+# Prefetching happens before squishd starts listening
+while [ "$(curl -s -o /dev/null -w "%{http_code}" --unix-socket "$DIR/squishd.sock" http:/x/status)" != "200" ]; do
+  if ! kill -0 $DAEMON 2> /dev/null; then
+    echo "squishd exited early:"
+    cat "$DIR/daemon.log"
+    exit 1
+  fi
+  sleep 0.5
+done
+
+ROOTFS="$DIR/cache/alpine/rootfs/alpine-rootfs-3.14-$ARCH"
+RELEASE=$(cat "$ROOTFS/etc/alpine-release" 2> /dev/null)
+if [ "$RELEASE" != "3.14-fixture" ] || [ ! -e "$ROOTFS.complete" ]; then
+  echo "Expected the fixture rootfs to be cached, got release '$RELEASE'"
+  cat "$DIR/daemon.log"
+  exit 1
+fi
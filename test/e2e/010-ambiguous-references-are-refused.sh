@@ -0,0 +1,25 @@
+#!/usr/bin/env bash
+
+# 010-ambiguous-references-are-refused
+# Assert that a container reference matching more than one container is
+# refused rather than acting on all of them, while an exact name still works.
+
+SQUISHFILE=./test/squishfiles/010-squishfile-no-ports.toml
+cargo -q run -p cli -- create --name ambiguous-one $SQUISHFILE > /dev/null || exit 1
+cargo -q run -p cli -- create --name ambiguous-two $SQUISHFILE > /dev/null || exit 1
+
+OUTPUT=$(cargo -q run -p cli -- stop ambiguous 2>&1)
+STATUS=$?
+RUNNING=$(cargo -q run -p cli -- ps | grep -c "ambiguous-")
+# Don't leave them behind for the next test, whatever happened
+cargo -q run -p cli -- stop ambiguous-one > /dev/null
+cargo -q run -p cli -- stop ambiguous-two > /dev/null
+
+if [ $STATUS -eq 0 ] || [[ "$OUTPUT" != *"matches more than one container"* ]]; then
+  echo "Expected stopping 'ambiguous' to fail, got status $STATUS:\n$OUTPUT"
+  exit 1
+fi
+if [ "$RUNNING" -ne 2 ]; then
+  echo "Expected both containers to still be running, but found $RUNNING"
+  exit 1
+fi
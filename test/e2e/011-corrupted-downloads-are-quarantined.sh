@@ -0,0 +1,55 @@
+#!/usr/bin/env bash
+
+# 011-corrupted-downloads-are-quarantined
+# Assert that an Alpine tarball that doesn't match its mirror's digest is
+# quarantined instead of being extracted, and that the container needing it
+# fails to be created with a checksum mismatch.
+
+# The host's architecture, in Alpine's naming, as squishd works it out
+case "$(uname -m)" in
+  i386|i486|i586|i686) ARCH=x86 ;;
+  arm64) ARCH=aarch64 ;;
+  armv7l|armv8l) ARCH=armv7 ;;
+  armv6l) ARCH=armhf ;;
+  *) ARCH=$(uname -m) ;;
+esac
+
+DIR=$(mktemp -d)
+trap 'kill $DAEMON 2> /dev/null; rm -rf "$DIR"' EXIT
+mkdir -p "$DIR/mirror/v3.14/releases"
+touch "$DIR/squishd.toml"
+cp -rL test/fixtures/mirror-corrupt/v3.14/releases/x86_64 "$DIR/mirror/v3.14/releases/$ARCH"
+
+./target/debug/daemon \
+  --config "$DIR/squishd.toml" \
+  --socket "$DIR/squishd.sock" \
+  --runtime-dir "$DIR/run" \
+  --state-dir "$DIR/state" \
+  --cache-dir "$DIR/cache" \
+  --no-prefetch \
+  --alpine-arch "$ARCH" \
+  --alpine-mirror "file://$DIR/mirror" > "$DIR/daemon.log" 2>&1 &
+DAEMON=$!
+while [ "$(curl -s -o /dev/null -w "%{http_code}" --unix-socket "$DIR/squishd.sock" http:/x/status)" != "200" ]; do
+  if ! kill -0 $DAEMON 2> /dev/null; then
+    echo "squishd exited early:"
+    cat "$DIR/daemon.log"
+    exit 1
+  fi
+  sleep 0.5
+done
+
+OUTPUT=$(SQUISH_HOST="$DIR/squishd.sock" cargo -q run -p cli -- create ./test/squishfiles/010-squishfile-no-ports.toml 2>&1)
+STATUS=$?
+if [ $STATUS -eq 0 ] || [[ "$OUTPUT" != *"checksum mismatch"* ]]; then
+  echo "Expected a checksum mismatch, got status $STATUS:\n$OUTPUT"
+  exit 1
+fi
+if [ -z "$(ls "$DIR/cache/quarantine" 2> /dev/null)" ]; then
+  echo "Expected the corrupted tarball to be quarantined"
+  exit 1
+fi
+if [ -e "$DIR/cache/alpine/rootfs/alpine-rootfs-3.14-$ARCH" ]; then
+  echo "Expected the corrupted tarball not to be extracted"
+  exit 1
+fi
@@ -37,10 +37,12 @@ stop_container() {
 log "Running build..."
 cargo build
 
-# Run daemon
-cargo -q run -p daemon &
+# Run daemon on its own socket, so as not to clash with a dev daemon
+SOCKET_DIR=$(mktemp -d)
+export SQUISH_HOST="unix://$SOCKET_DIR/squishd.sock"
+cargo -q run -p daemon -- --socket "$SOCKET_DIR/squishd.sock" &
 # Await daemon up
-while [ "`curl -s -o /dev/null -w "%{http_code}" --unix-socket /tmp/squishd.sock http:/x/status`" != "200" ]; do
+while [ "`curl -s -o /dev/null -w "%{http_code}" --unix-socket $SOCKET_DIR/squishd.sock http:/x/status`" != "200" ]; do
   sleep 0.5
 done
 sleep 1
@@ -89,6 +91,7 @@ done
 
 # Clean up
 kill $DAEMON
+rm -rf "$SOCKET_DIR"
 
 if [ $PASSED -ne $TOTAL ]; then
   log "${RED}Failed $((TOTAL - PASSED))/$TOTAL tests!$DEFAULT"